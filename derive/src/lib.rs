@@ -0,0 +1,208 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Lit, Meta, NestedMeta, Type,
+};
+
+/// derives `logicaldecoding::replication::FromChange` for a struct with named fields
+///
+/// every field is read from the column of the same name unless it is annotated with:
+///
+/// - `#[column(rename = "name")]` to read it from a differently named column
+/// - `#[column(skip)]` to not read it at all. it is set to its `Default` on insert.
+/// - `#[key]` to make it part of the key identifying the row. multiple key fields form a tuple.
+///
+/// fields of type `Option<T>` are optional: they are `None` when the column is null or missing.
+#[proc_macro_derive(FromChange, attributes(column, key))]
+pub fn derive_from_change(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    column: String,
+    key: bool,
+    skip: bool,
+    optional: bool,
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "FromChange can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "FromChange can only be derived for structs",
+            ))
+        }
+    };
+
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let ident = field.ident.clone().unwrap();
+            let mut parsed = Field {
+                column: ident.to_string(),
+                ident,
+                ty: field.ty.clone(),
+                key: false,
+                skip: false,
+                optional: is_option(&field.ty),
+            };
+
+            for attr in &field.attrs {
+                if attr.path.is_ident("key") {
+                    parsed.key = true;
+                } else if attr.path.is_ident("column") {
+                    parse_column_attribute(attr.parse_meta()?, &mut parsed)?;
+                }
+            }
+
+            if parsed.key && parsed.skip {
+                return Err(Error::new_spanned(
+                    field,
+                    "a #[key] field cannot be skipped",
+                ));
+            }
+            Ok(parsed)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let keys = fields.iter().filter(|field| field.key).collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "FromChange requires at least one field marked #[key]",
+        ));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let replication = quote!(::logicaldecoding::replication);
+    let private = quote!(#replication::__private);
+
+    let key_types = keys.iter().map(|field| &field.ty);
+    let key_idents = keys.iter().map(|field| &field.ident);
+    let key_columns = keys.iter().map(|field| &field.column).collect::<Vec<_>>();
+    let (key_type, key, key_from_columns) = if keys.len() == 1 {
+        let field = keys[0];
+        let (ty, ident, column) = (&field.ty, &field.ident, &field.column);
+        (
+            quote!(#ty),
+            quote!(::std::clone::Clone::clone(&self.#ident)),
+            quote!(#private::required(columns, #column)),
+        )
+    } else {
+        (
+            quote!((#(#key_types,)*)),
+            quote!((#(::std::clone::Clone::clone(&self.#key_idents),)*)),
+            quote!(::std::result::Result::Ok((
+                #(#private::required(columns, #key_columns)?,)*
+            ))),
+        )
+    };
+
+    let inserts = fields.iter().map(|field| {
+        let (ident, column) = (&field.ident, &field.column);
+        if field.skip {
+            quote!(#ident: ::std::default::Default::default())
+        } else if field.optional {
+            quote!(#ident: #private::optional(&change.new, #column)?)
+        } else {
+            quote!(#ident: #private::required(&change.new, #column)?)
+        }
+    });
+
+    let updates = fields.iter().filter(|field| !field.skip).map(|field| {
+        let (ident, column) = (&field.ident, &field.column);
+        quote!(#private::update(&mut self.#ident, &change.new, #column)?;)
+    });
+
+    Ok(quote! {
+        impl #impl_generics #replication::FromChange for #name #ty_generics #where_clause {
+            type Key = #key_type;
+
+            fn key(&self) -> Self::Key {
+                #key
+            }
+
+            fn key_from_change(
+                change: &#replication::Change,
+            ) -> ::std::result::Result<Self::Key, #replication::ReplicationError> {
+                let columns = #private::key_columns(change, &[#(#key_columns),*]);
+                #key_from_columns
+            }
+
+            fn from_insert(
+                change: &#replication::Change,
+            ) -> ::std::result::Result<Self, #replication::ReplicationError> {
+                ::std::result::Result::Ok(Self {
+                    #(#inserts,)*
+                })
+            }
+
+            fn apply_update(
+                &mut self,
+                change: &#replication::Change,
+            ) -> ::std::result::Result<(), #replication::ReplicationError> {
+                #(#updates)*
+                ::std::result::Result::Ok(())
+            }
+        }
+    })
+}
+
+fn parse_column_attribute(meta: Meta, field: &mut Field) -> Result<(), Error> {
+    let list = match meta {
+        Meta::List(list) => list,
+        meta => {
+            return Err(Error::new_spanned(
+                meta,
+                "expected #[column(rename = \"...\")] or #[column(skip)]",
+            ))
+        }
+    };
+
+    for nested in list.nested {
+        match nested {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => field.skip = true,
+            NestedMeta::Meta(Meta::NameValue(name_value)) if name_value.path.is_ident("rename") => {
+                match name_value.lit {
+                    Lit::Str(column) => field.column = column.value(),
+                    lit => return Err(Error::new_spanned(lit, "expected a string literal")),
+                }
+            }
+            nested => {
+                return Err(Error::new_spanned(
+                    nested,
+                    "unknown column attribute, expected `rename = \"...\"` or `skip`",
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
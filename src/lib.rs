@@ -0,0 +1,4 @@
+// lets code generated by the derive macros refer to `::logicaldecoding` inside this crate too
+extern crate self as logicaldecoding;
+
+pub mod replication;
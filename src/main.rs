@@ -1,11 +1,11 @@
-mod replication;
 mod types;
 use std::env;
 
 use anyhow::Result;
-use replication::Transaction;
+use futures::TryStreamExt;
+use logicaldecoding::replication::{self, ReplicationConfig, ReplicationStream};
 use sqlx::{migrate::Migrator, PgPool};
-use tokio::task;
+use tracing::info;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -18,19 +18,21 @@ async fn main() -> Result<()> {
         .init();
 
     let m = Migrator::new(std::path::Path::new("./migrations")).await?;
-    let pool = PgPool::connect(&env::var("DATABASE_URL")?).await?;
+    let database_url = env::var("DATABASE_URL")?;
+    let pool = PgPool::connect(&database_url).await?;
     m.run(&pool).await?;
 
-    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel::<()>();
-    let (tx, _rx) = tokio::sync::broadcast::channel::<Transaction>(100);
+    let config = ReplicationConfig::from_url(&database_url)?;
 
-    let streaming_handle =
-        task::spawn(async { replication::start_streaming_changes("postgres", ready_tx, tx).await });
-
-    // block waiting for replication
-    ready_rx.await.unwrap();
-
-    streaming_handle.await.unwrap().unwrap();
+    let mut stream = ReplicationStream::connect(config).await?;
+    while let Some(transaction) = stream.try_next().await? {
+        info!(
+            "Transaction {} with {} changes",
+            transaction.xid,
+            transaction.events.len()
+        );
+        stream.ack(&transaction);
+    }
 
     Ok(())
 }
@@ -0,0 +1,83 @@
+use super::{Lsn, Value};
+
+/// the kind of modification a `Change` describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Insert,
+    Update,
+    Delete,
+    /// every row of the table was removed. a statement truncating several tables is delivered as
+    /// one change for each.
+    Truncate,
+}
+
+/// a named column value of a row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// the oid of the column's type if the plugin sends it
+    pub type_oid: Option<u32>,
+    pub value: Value,
+}
+
+/// the tables and options of a `TRUNCATE` statement
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Truncate {
+    /// every table truncated by the statement, including those truncated through `CASCADE`
+    pub tables: Vec<String>,
+    pub cascade: bool,
+    /// whether `RESTART IDENTITY` reset the sequences owned by the truncated columns
+    pub restart_identity: bool,
+}
+
+/// a single row change independent of the output plugin that decoded it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// the schema qualified table name without quotes, e.g. `public.tenants` or
+    /// `My Schema.Tenant Events`, whichever plugin decoded it
+    pub table: String,
+    pub op: Op,
+    /// the replica identity of the row before an update or delete. depending on the table's
+    /// `REPLICA IDENTITY` this is the key columns, the full row or empty.
+    pub old: Vec<Column>,
+    /// the row after an insert or update
+    pub new: Vec<Column>,
+    /// the position of the change in the write-ahead log
+    pub lsn: Lsn,
+    /// the statement of an `Op::Truncate`, whose `old` and `new` are empty
+    pub truncate: Option<Truncate>,
+}
+
+impl Change {
+    /// the value of a column of the new row
+    pub fn new_value(&self, name: &str) -> Option<&Value> {
+        find(&self.new, name)
+    }
+
+    /// the value of a column of the old row
+    pub fn old_value(&self, name: &str) -> Option<&Value> {
+        find(&self.old, name)
+    }
+
+    /// a change for each table of a truncate decoded as a single change, or the change itself
+    pub(crate) fn per_table(self) -> Vec<Change> {
+        match &self.truncate {
+            Some(truncate) if truncate.tables.len() > 1 => truncate
+                .tables
+                .iter()
+                .map(|table| Change {
+                    table: table.clone(),
+                    ..self.clone()
+                })
+                .collect(),
+            _ => vec![self],
+        }
+    }
+}
+
+fn find<'a>(columns: &'a [Column], name: &str) -> Option<&'a Value> {
+    columns
+        .iter()
+        .find(|column| column.name == name)
+        .map(|column| &column.value)
+}
@@ -0,0 +1,114 @@
+use std::{error::Error as _, io};
+use thiserror::Error;
+use tokio_postgres::error::SqlState;
+
+/// errors raised while establishing or consuming a replication stream
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    /// the replication configuration is invalid
+    #[error("invalid replication config: {0}")]
+    Config(String),
+    /// the connection to the server failed or was lost
+    #[error("replication connection error: {0}")]
+    Connection(#[from] tokio_postgres::Error),
+    /// the server sent a message that does not follow the replication protocol
+    #[error("replication protocol error: {0}")]
+    Protocol(String),
+    /// a message payload could not be decoded
+    #[error("failed to decode replication message: {0}")]
+    Decode(#[from] prost::DecodeError),
+    /// the replication slot could not be created or reused
+    #[error("replication slot {slot_name:?}: {message}")]
+    Slot {
+        slot_name: String,
+        message: String,
+        /// the error of the query on the slot, if it failed
+        source: Option<tokio_postgres::Error>,
+    },
+    /// the output plugin produced a message that cannot be interpreted
+    #[error("output plugin error: {0}")]
+    Plugin(String),
+    /// a change could not be mapped onto a struct
+    #[error("failed to map change: {0}")]
+    Mapping(String),
+    /// a consumer fell behind the replication stream and missed transactions, or a `Subscription`
+    /// missed notifications
+    #[error("consumer lagged behind the replication stream and missed {0} transactions or notifications")]
+    Lagged(u64),
+    /// a prepared transaction was committed whose prepare was never received, e.g. because the
+    /// consumer lagged behind or started after it
+    #[error("COMMIT PREPARED of unknown prepared transaction {0:?}")]
+    UnknownPrepared(String),
+    /// changes of a large transaction could not be spilled to or read back from disk
+    #[error("failed to spill changes to disk: {0}")]
+    Spill(#[from] std::io::Error),
+    /// every consumer of the replication stream has gone away
+    #[error("no consumers are subscribed to the replication stream")]
+    ConsumerGone,
+}
+
+impl ReplicationError {
+    /// whether the error may not recur after reconnecting, such as a lost connection or a slot
+    /// still active for the connection being replaced, unlike e.g. missing privileges or objects
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ReplicationError::Connection(err)
+            | ReplicationError::Slot {
+                source: Some(err), ..
+            } => is_transient(err),
+            _ => false,
+        }
+    }
+}
+
+fn is_transient(err: &tokio_postgres::Error) -> bool {
+    match err.code() {
+        Some(code) => is_transient_state(code),
+        // lost connections and I/O errors, unlike invalid configurations or authentication errors
+        None => err.is_closed() || err.source().is_some_and(|source| source.is::<io::Error>()),
+    }
+}
+
+/// whether an error with the SQLSTATE `code` reported by the server may not recur
+fn is_transient_state(code: &SqlState) -> bool {
+    // connection exceptions, transaction rollbacks, insufficient resources and shutdowns
+    ["08", "40", "53", "57P"]
+        .iter()
+        .any(|class| code.code().starts_with(class))
+        || *code == SqlState::OBJECT_IN_USE
+        || *code == SqlState::LOCK_NOT_AVAILABLE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_transient_state() {
+        for code in [
+            SqlState::OBJECT_IN_USE,
+            SqlState::CONNECTION_FAILURE,
+            SqlState::ADMIN_SHUTDOWN,
+            SqlState::CANNOT_CONNECT_NOW,
+            SqlState::TOO_MANY_CONNECTIONS,
+            SqlState::T_R_SERIALIZATION_FAILURE,
+        ] {
+            assert!(is_transient_state(&code), "{:?}", code);
+        }
+        for code in [
+            SqlState::INSUFFICIENT_PRIVILEGE,
+            SqlState::UNDEFINED_OBJECT,
+            SqlState::INVALID_PASSWORD,
+            SqlState::INVALID_CATALOG_NAME,
+            SqlState::SYNTAX_ERROR,
+        ] {
+            assert!(!is_transient_state(&code), "{:?}", code);
+        }
+        assert!(!ReplicationError::Slot {
+            slot_name: "tenants".to_string(),
+            message: "no slot returned".to_string(),
+            source: None,
+        }
+        .is_transient());
+    }
+}
@@ -0,0 +1,159 @@
+use super::{ReplicationError, Transaction};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use tracing::trace;
+
+/// delivers every transaction to all subscribers, waiting for the slowest one
+///
+/// unlike a `broadcast` channel which overwrites transactions a lagging subscriber has not received
+/// yet, sending waits until every subscriber has room for the transaction. used with
+/// `ReplicationStream::fanout` the backpressure reaches the server so no transaction is ever dropped.
+#[derive(Debug, Clone)]
+pub struct Fanout {
+    senders: Arc<Mutex<Vec<mpsc::Sender<Arc<Transaction>>>>>,
+    capacity: usize,
+}
+
+impl Fanout {
+    /// creates a fanout whose subscribers buffer up to `capacity` transactions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            senders: Default::default(),
+            capacity,
+        }
+    }
+
+    /// subscribes to every transaction sent from now on. dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> mpsc::Receiver<Arc<Transaction>> {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// sends `transaction` to every subscriber, waiting while any of their buffers is full
+    ///
+    /// fails with `ConsumerGone` if there are no subscribers left.
+    pub async fn send(&self, transaction: Arc<Transaction>) -> Result<(), ReplicationError> {
+        let senders = self.senders.lock().unwrap().clone();
+
+        let mut delivered = false;
+        for sender in &senders {
+            if sender.capacity() == 0 {
+                trace!("Waiting for a subscriber to catch up");
+            }
+            delivered |= sender.send(transaction.clone()).await.is_ok();
+        }
+
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|sender| !sender.is_closed());
+        match delivered {
+            true => Ok(()),
+            false => Err(ReplicationError::ConsumerGone),
+        }
+    }
+}
+
+/// the receiving end of either a `broadcast` channel or a `Fanout`
+///
+/// a broadcast receiver which lagged behind and missed transactions fails with `Lagged` rather
+/// than skipping them.
+#[derive(Debug)]
+pub enum TransactionReceiver {
+    Broadcast(broadcast::Receiver<Arc<Transaction>>),
+    Fanout(mpsc::Receiver<Arc<Transaction>>),
+}
+
+impl TransactionReceiver {
+    /// the next transaction, or `None` once the sender is gone
+    pub async fn recv(&mut self) -> Result<Option<Arc<Transaction>>, ReplicationError> {
+        match self {
+            TransactionReceiver::Broadcast(rx) => match rx.recv().await {
+                Ok(transaction) => Ok(Some(transaction)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    Err(ReplicationError::Lagged(skipped))
+                }
+                Err(broadcast::error::RecvError::Closed) => Ok(None),
+            },
+            TransactionReceiver::Fanout(rx) => Ok(rx.recv().await),
+        }
+    }
+}
+
+impl From<broadcast::Receiver<Arc<Transaction>>> for TransactionReceiver {
+    fn from(rx: broadcast::Receiver<Arc<Transaction>>) -> Self {
+        TransactionReceiver::Broadcast(rx)
+    }
+}
+
+impl From<mpsc::Receiver<Arc<Transaction>>> for TransactionReceiver {
+    fn from(rx: mpsc::Receiver<Arc<Transaction>>) -> Self {
+        TransactionReceiver::Fanout(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::test_util::transaction;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_fanout_waits_for_slowest() -> Result<(), ReplicationError> {
+        let fanout = Fanout::new(1);
+        let mut fast = TransactionReceiver::from(fanout.subscribe());
+        let mut slow = TransactionReceiver::from(fanout.subscribe());
+
+        fanout.send(Arc::new(transaction(1, vec![]))).await?;
+        assert_eq!(
+            fast.recv().await?.map(|transaction| transaction.xid),
+            Some(1)
+        );
+
+        // the slow subscriber's buffer is full so the next send waits for it
+        let sending = tokio::spawn({
+            let fanout = fanout.clone();
+            async move { fanout.send(Arc::new(transaction(2, vec![]))).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!sending.is_finished());
+
+        assert_eq!(
+            slow.recv().await?.map(|transaction| transaction.xid),
+            Some(1)
+        );
+        sending.await.unwrap()?;
+        assert_eq!(
+            fast.recv().await?.map(|transaction| transaction.xid),
+            Some(2)
+        );
+        assert_eq!(
+            slow.recv().await?.map(|transaction| transaction.xid),
+            Some(2)
+        );
+
+        drop(fast);
+        drop(slow);
+        assert!(matches!(
+            fanout.send(Arc::new(transaction(3, vec![]))).await,
+            Err(ReplicationError::ConsumerGone)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_lag() -> Result<(), ReplicationError> {
+        let (tx, rx) = broadcast::channel(1);
+        let mut rx = TransactionReceiver::from(rx);
+
+        tx.send(Arc::new(transaction(1, vec![]))).unwrap();
+        tx.send(Arc::new(transaction(2, vec![]))).unwrap();
+        assert!(matches!(rx.recv().await, Err(ReplicationError::Lagged(1))));
+        assert_eq!(rx.recv().await?.map(|transaction| transaction.xid), Some(2));
+
+        drop(tx);
+        assert!(rx.recv().await?.is_none());
+        Ok(())
+    }
+}
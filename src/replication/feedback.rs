@@ -0,0 +1,214 @@
+use super::{protocol::to_pg_timestamp, Lsn, Transaction};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::SystemTime,
+};
+
+/// the set of consumers whose acknowledgements gate the feedback sent to the server
+///
+/// the replication stream only reports positions as flushed/applied once every registered consumer
+/// has acknowledged them, so unacknowledged transactions are redelivered from a persistent slot after
+/// a restart.
+#[derive(Debug, Clone, Default)]
+pub struct Acknowledgements {
+    consumers: Arc<Mutex<Vec<Weak<AtomicU64>>>>,
+}
+
+impl Acknowledgements {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a new consumer. it is deregistered once the returned `Acker` and all its clones drop.
+    ///
+    /// the consumer starts at the position already acknowledged by the others so registering late
+    /// does not hold feedback back.
+    pub fn register(&self) -> Acker {
+        let mut consumers = self.consumers.lock().unwrap();
+        let acknowledged = Self::minimum(&mut consumers).unwrap_or_default();
+
+        let acker = Acker {
+            acknowledged: Arc::new(AtomicU64::new(acknowledged.as_u64())),
+        };
+        consumers.push(Arc::downgrade(&acker.acknowledged));
+        acker
+    }
+
+    /// the minimum position acknowledged by all live consumers
+    pub(crate) fn acknowledged(&self) -> Option<Lsn> {
+        Self::minimum(&mut self.consumers.lock().unwrap())
+    }
+
+    fn minimum(consumers: &mut Vec<Weak<AtomicU64>>) -> Option<Lsn> {
+        consumers.retain(|consumer| consumer.strong_count() > 0);
+        consumers
+            .iter()
+            .filter_map(|consumer| consumer.upgrade())
+            .map(|acknowledged| Lsn::new(acknowledged.load(Ordering::SeqCst)))
+            .min()
+    }
+}
+
+/// handle through which a consumer acknowledges transactions it has durably processed
+#[derive(Debug, Clone)]
+pub struct Acker {
+    acknowledged: Arc<AtomicU64>,
+}
+
+impl Acker {
+    /// acknowledges the transaction and every transaction committed before it
+    pub fn ack(&self, transaction: &Transaction) {
+        self.ack_lsn(transaction.commit_lsn);
+    }
+
+    /// acknowledges every transaction committed at or before `lsn`
+    pub(crate) fn ack_lsn(&self, lsn: Lsn) {
+        self.acknowledged.fetch_max(lsn.as_u64(), Ordering::SeqCst);
+    }
+}
+
+/// tracks the wal positions reported to the server in standby status updates
+#[derive(Debug, Default)]
+pub(crate) struct Feedback {
+    /// end of the last wal received from the server
+    written: Lsn,
+    /// commit lsn of the last transaction handed to consumers
+    last_commit: Lsn,
+    /// whether a transaction has begun but not yet committed
+    in_transaction: bool,
+    /// whether transactions streamed before they commit are in progress
+    streaming: bool,
+    /// the last position reported as flushed which never moves backwards
+    flushed: Lsn,
+}
+
+impl Feedback {
+    pub fn received(&mut self, wal_end: Lsn) {
+        self.written = self.written.max(wal_end);
+    }
+
+    pub fn begin(&mut self) {
+        self.in_transaction = true;
+    }
+
+    pub fn streaming(&mut self, in_progress: bool) {
+        self.streaming = in_progress;
+    }
+
+    /// ends a transaction which is not handed to consumers so it needs no acknowledgement
+    pub fn skip(&mut self) {
+        self.in_transaction = false;
+    }
+
+    pub fn commit(&mut self, commit_lsn: Lsn) {
+        self.in_transaction = false;
+        self.last_commit = self.last_commit.max(commit_lsn);
+    }
+
+    /// advances the position that is safe to report as flushed given what consumers have acknowledged.
+    ///
+    /// once every delivered transaction is acknowledged and none is in progress, everything
+    /// received is either delivered or irrelevant to this slot so the written position is reported.
+    /// this lets the server recycle wal generated by other databases while no changes flow.
+    pub fn flushed(&mut self, acknowledged: Option<Lsn>) -> Lsn {
+        let flushed = match acknowledged {
+            Some(acknowledged)
+                if !self.in_transaction && !self.streaming && acknowledged >= self.last_commit =>
+            {
+                self.written.max(acknowledged)
+            }
+            Some(acknowledged) => acknowledged,
+            None => self.flushed,
+        };
+        self.flushed = self.flushed.max(flushed);
+        self.flushed
+    }
+
+    /// builds a standby status update ('r') message
+    ///
+    /// see here for format details: https://www.postgresql.org/docs/current/protocol-replication.html
+    pub fn status_update(&mut self, acknowledged: Option<Lsn>, reply_requested: bool) -> Bytes {
+        let flushed = self.flushed(acknowledged);
+
+        let mut status = BytesMut::with_capacity(34);
+        status.put_u8(b'r');
+        status.put_u64(self.written.max(flushed).as_u64());
+        status.put_u64(flushed.as_u64());
+        status.put_u64(flushed.as_u64());
+        status.put_i64(to_pg_timestamp(SystemTime::now()));
+        status.put_u8(reply_requested as u8);
+        status.freeze()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::test_util::transaction;
+
+    #[test]
+    fn test_flushed_waits_for_ack() {
+        let mut feedback = Feedback::default();
+
+        feedback.begin();
+        feedback.received(Lsn::new(100));
+        feedback.commit(Lsn::new(100));
+        feedback.received(Lsn::new(150));
+        assert_eq!(feedback.flushed(Some(Lsn::new(0))), Lsn::new(0));
+        assert_eq!(feedback.flushed(Some(Lsn::new(100))), Lsn::new(150));
+
+        feedback.begin();
+        feedback.received(Lsn::new(200));
+        assert_eq!(feedback.flushed(Some(Lsn::new(100))), Lsn::new(150));
+        assert_eq!(feedback.flushed(None), Lsn::new(150));
+
+        // filtered out transactions are never acknowledged
+        feedback.skip();
+        assert_eq!(feedback.flushed(Some(Lsn::new(100))), Lsn::new(200));
+
+        // nothing past a transaction streamed before it commits is reported until it ends
+        feedback.streaming(true);
+        feedback.received(Lsn::new(300));
+        assert_eq!(feedback.flushed(Some(Lsn::new(100))), Lsn::new(200));
+        feedback.streaming(false);
+        assert_eq!(feedback.flushed(Some(Lsn::new(100))), Lsn::new(300));
+    }
+
+    #[test]
+    fn test_minimum_of_consumers() {
+        let acknowledgements = Acknowledgements::new();
+        assert_eq!(acknowledgements.acknowledged(), None);
+
+        let fast = acknowledgements.register();
+        let slow = acknowledgements.register();
+        fast.ack(&transaction(200, vec![]));
+        slow.ack(&transaction(100, vec![]));
+        assert_eq!(acknowledgements.acknowledged(), Some(Lsn::new(100)));
+
+        // late consumers start from the current minimum
+        let late = acknowledgements.register();
+        assert_eq!(acknowledgements.acknowledged(), Some(Lsn::new(100)));
+
+        drop(slow);
+        late.ack(&transaction(300, vec![]));
+        assert_eq!(acknowledgements.acknowledged(), Some(Lsn::new(200)));
+    }
+
+    #[test]
+    fn test_status_update() {
+        let mut feedback = Feedback::default();
+        feedback.received(Lsn::new(0x0102));
+
+        let status = feedback.status_update(Some(Lsn::new(0x0101)), true);
+        assert_eq!(status.len(), 34);
+        assert_eq!(status[0], b'r');
+        assert_eq!(&status[1..9], &0x0102_u64.to_be_bytes());
+        assert_eq!(&status[9..17], &0x0102_u64.to_be_bytes());
+        assert_eq!(&status[17..25], &0x0102_u64.to_be_bytes());
+        assert_eq!(status[33], 1);
+    }
+}
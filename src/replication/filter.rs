@@ -0,0 +1,179 @@
+use super::{Change, Op};
+
+/// selects the changes broadcast to subscribers
+///
+/// tables are matched by their schema qualified name, e.g. `public.tenants`, or by a glob where `*`
+/// matches any sequence of characters and `?` a single character, e.g. `public.*`. a change is kept
+/// if its table matches an included pattern (or none are configured), matches no excluded pattern
+/// and its operation is selected (or none are configured).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Filter {
+    include_tables: Vec<String>,
+    exclude_tables: Vec<String>,
+    ops: Vec<Op>,
+    columns: Vec<(String, Vec<String>)>,
+}
+
+impl Filter {
+    /// a filter keeping every change
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// only keeps changes of tables matching `pattern` or any other included pattern
+    pub fn include_table(mut self, pattern: impl Into<String>) -> Self {
+        self.include_tables.push(pattern.into());
+        self
+    }
+
+    /// drops changes of tables matching `pattern`, even if they are included
+    pub fn exclude_table(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude_tables.push(pattern.into());
+        self
+    }
+
+    /// only keeps changes with one of `ops`
+    pub fn ops(mut self, ops: impl IntoIterator<Item = Op>) -> Self {
+        self.ops.extend(ops);
+        self
+    }
+
+    /// only keeps `columns` of the tables matching `pattern`. the first matching projection applies.
+    pub fn columns<I, S>(mut self, pattern: impl Into<String>, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.columns.push((
+            pattern.into(),
+            columns.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// whether changes of `table` are kept
+    pub fn includes_table(&self, table: &str) -> bool {
+        (self.include_tables.is_empty()
+            || self
+                .include_tables
+                .iter()
+                .any(|pattern| glob_match(pattern, table)))
+            && !self
+                .exclude_tables
+                .iter()
+                .any(|pattern| glob_match(pattern, table))
+    }
+
+    /// the change with its columns projected, or `None` if it is filtered out
+    pub fn apply(&self, mut change: Change) -> Option<Change> {
+        if !self.includes_table(&change.table)
+            || (!self.ops.is_empty() && !self.ops.contains(&change.op))
+        {
+            return None;
+        }
+
+        if let Some((_, columns)) = self
+            .columns
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, &change.table))
+        {
+            change.old.retain(|column| columns.contains(&column.name));
+            change.new.retain(|column| columns.contains(&column.name));
+        }
+        Some(change)
+    }
+}
+
+/// matches `text` against a glob `pattern` supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` and the text it was matched against, to backtrack to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::{test_util, Value};
+
+    fn change(table: &str, op: Op) -> Change {
+        let column = |name: &str| test_util::column(name, Value::Int4(1));
+        test_util::change(
+            table,
+            op,
+            vec![column("id")],
+            vec![column("id"), column("name"), column("body")],
+        )
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("public.tenants", "public.tenants"));
+        assert!(!glob_match("public.tenants", "public.tenants_audit"));
+        assert!(glob_match("public.*", "public.tenants"));
+        assert!(glob_match("*.tenant?", "audit.tenants"));
+        assert!(glob_match("*_audit", "public.tenants_audit"));
+        assert!(glob_match("p*c*s", "public.tenants"));
+        assert!(!glob_match("*.users", "public.tenants"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_apply() {
+        let filter = Filter::new()
+            .include_table("public.*")
+            .exclude_table("public.*_audit")
+            .ops([Op::Insert, Op::Update])
+            .columns("public.tenants", ["id", "name"]);
+
+        let projected = filter.apply(change("public.tenants", Op::Insert)).unwrap();
+        assert_eq!(
+            projected
+                .new
+                .iter()
+                .map(|column| column.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["id", "name"]
+        );
+        assert_eq!(projected.old.len(), 1);
+        assert_eq!(
+            filter
+                .apply(change("public.users", Op::Update))
+                .map(|change| change.new.len()),
+            Some(3)
+        );
+
+        assert_eq!(filter.apply(change("public.tenants", Op::Delete)), None);
+        assert_eq!(
+            filter.apply(change("public.tenants_audit", Op::Insert)),
+            None
+        );
+        assert_eq!(filter.apply(change("private.tenants", Op::Insert)), None);
+
+        assert!(Filter::new().includes_table("private.tenants"));
+    }
+}
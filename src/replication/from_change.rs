@@ -0,0 +1,371 @@
+use super::{Change, Column, ReplicationError, Value};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+/// conversion from a column value into a rust type
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError>;
+}
+
+fn unexpected<T>(expected: &str, value: &Value) -> Result<T, ReplicationError> {
+    Err(ReplicationError::Mapping(format!(
+        "expected {} but got {:?}",
+        expected, value
+    )))
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Array(values) => values.iter().map(T::from_value).collect(),
+            value => unexpected("an array", value),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Bool(value) => Ok(*value),
+            value => unexpected("a bool", value),
+        }
+    }
+}
+
+impl FromValue for i16 {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Int2(value) => Ok(*value),
+            value => unexpected("an int2", value),
+        }
+    }
+}
+
+impl FromValue for i32 {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Int2(value) => Ok(*value as i32),
+            Value::Int4(value) => Ok(*value),
+            value => unexpected("an int2 or int4", value),
+        }
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Int2(value) => Ok(*value as i64),
+            Value::Int4(value) => Ok(*value as i64),
+            Value::Int8(value) => Ok(*value),
+            value => unexpected("an integer", value),
+        }
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Float(value) => Ok(*value),
+            value => unexpected("a float", value),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Text(value) | Value::Numeric(value) => Ok(value.clone()),
+            value => unexpected("text", value),
+        }
+    }
+}
+
+impl FromValue for Uuid {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Uuid(value) => Ok(*value),
+            value => unexpected("a uuid", value),
+        }
+    }
+}
+
+impl FromValue for Bytes {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Bytea(value) => Ok(value.clone()),
+            value => unexpected("a bytea", value),
+        }
+    }
+}
+
+impl FromValue for NaiveDateTime {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Timestamp(value) => Ok(*value),
+            value => unexpected("a timestamp", value),
+        }
+    }
+}
+
+impl FromValue for DateTime<Utc> {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Timestamptz(value) => Ok(*value),
+            value => unexpected("a timestamptz", value),
+        }
+    }
+}
+
+impl FromValue for NaiveDate {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Date(value) => Ok(*value),
+            value => unexpected("a date", value),
+        }
+    }
+}
+
+impl FromValue for serde_json::Value {
+    fn from_value(value: &Value) -> Result<Self, ReplicationError> {
+        match value {
+            Value::Json(value) => Ok(value.clone()),
+            value => unexpected("json", value),
+        }
+    }
+}
+
+/// a struct that mirrors the rows of a table and can be kept up to date from its changes
+///
+/// usually implemented with `#[derive(FromChange)]`.
+pub trait FromChange: Sized {
+    /// the type identifying a row, e.g. the primary key
+    type Key;
+
+    /// the key of this row
+    fn key(&self) -> Self::Key;
+
+    /// the key of the row a change applies to. for updates and deletes this is read from the old
+    /// row if the replica identity contains it.
+    fn key_from_change(change: &Change) -> Result<Self::Key, ReplicationError>;
+
+    /// builds the row inserted by an insert
+    fn from_insert(change: &Change) -> Result<Self, ReplicationError>;
+
+    /// applies the columns of an update. columns which are not sent, such as unchanged TOASTed
+    /// values, keep their current value.
+    fn apply_update(&mut self, change: &Change) -> Result<(), ReplicationError>;
+
+    /// the key of the row removed by a delete
+    fn key_from_delete(change: &Change) -> Result<Self::Key, ReplicationError> {
+        Self::key_from_change(change)
+    }
+}
+
+/// support for the code generated by `#[derive(FromChange)]`
+#[doc(hidden)]
+pub mod __private {
+    use super::*;
+
+    fn find<'a>(columns: &'a [Column], name: &str) -> Option<&'a Value> {
+        columns
+            .iter()
+            .find(|column| column.name == name)
+            .map(|column| &column.value)
+    }
+
+    fn convert<T: FromValue>(value: &Value, name: &str) -> Result<T, ReplicationError> {
+        T::from_value(value).map_err(|err| match err {
+            ReplicationError::Mapping(message) => {
+                ReplicationError::Mapping(format!("column {}: {}", name, message))
+            }
+            err => err,
+        })
+    }
+
+    pub fn required<T: FromValue>(columns: &[Column], name: &str) -> Result<T, ReplicationError> {
+        let value = find(columns, name)
+            .ok_or_else(|| ReplicationError::Mapping(format!("missing column {}", name)))?;
+        convert(value, name)
+    }
+
+    pub fn optional<T: FromValue + Default>(
+        columns: &[Column],
+        name: &str,
+    ) -> Result<T, ReplicationError> {
+        match find(columns, name) {
+            Some(value) => convert(value, name),
+            None => Ok(T::default()),
+        }
+    }
+
+    pub fn update<T: FromValue>(
+        field: &mut T,
+        columns: &[Column],
+        name: &str,
+    ) -> Result<(), ReplicationError> {
+        match find(columns, name) {
+            None | Some(Value::UnchangedToast) => Ok(()),
+            Some(value) => {
+                *field = convert(value, name)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// the old row if it holds all key columns, otherwise the new one
+    pub fn key_columns<'a>(change: &'a Change, names: &[&str]) -> &'a [Column] {
+        if !change.old.is_empty() && names.iter().all(|name| find(&change.old, name).is_some()) {
+            &change.old
+        } else {
+            &change.new
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::{
+        test_util::{self, column},
+        Change, Column, FromChange, Op, ReplicationError, Value,
+    };
+    use uuid::Uuid;
+
+    #[derive(Debug, PartialEq, FromChange)]
+    struct Tenant {
+        #[key]
+        id: Uuid,
+        #[column(rename = "name")]
+        display_name: String,
+        description: Option<String>,
+        tags: Vec<String>,
+        #[column(skip)]
+        xmin: Option<i64>,
+    }
+
+    #[derive(Debug, PartialEq, FromChange)]
+    struct Membership {
+        #[key]
+        tenant_id: i64,
+        #[key]
+        user_id: i64,
+        role: String,
+    }
+
+    fn change(op: Op, old: Vec<Column>, new: Vec<Column>) -> Change {
+        test_util::change("public.tenants", op, old, new)
+    }
+
+    #[test]
+    fn test_derive() -> Result<(), ReplicationError> {
+        let id = Uuid::new_v4();
+
+        let insert = change(
+            Op::Insert,
+            vec![],
+            vec![
+                column("id", Value::Uuid(id)),
+                column("name", Value::Text("tenant".to_string())),
+                column("description", Value::Null),
+                column("tags", Value::Array(vec![Value::Text("a".to_string())])),
+            ],
+        );
+        let mut tenant = Tenant::from_insert(&insert)?;
+        assert_eq!(
+            tenant,
+            Tenant {
+                id,
+                display_name: "tenant".to_string(),
+                description: None,
+                tags: vec!["a".to_string()],
+                xmin: None,
+            }
+        );
+        assert_eq!(tenant.key(), id);
+        assert_eq!(Tenant::key_from_change(&insert)?, id);
+
+        let update = change(
+            Op::Update,
+            vec![],
+            vec![
+                column("id", Value::Uuid(id)),
+                column("name", Value::UnchangedToast),
+                column("description", Value::Text("updated".to_string())),
+            ],
+        );
+        tenant.apply_update(&update)?;
+        assert_eq!(tenant.display_name, "tenant");
+        assert_eq!(tenant.description.as_deref(), Some("updated"));
+        assert_eq!(tenant.tags, vec!["a".to_string()]);
+
+        let delete = change(Op::Delete, vec![column("id", Value::Uuid(id))], vec![]);
+        assert_eq!(Tenant::key_from_delete(&delete)?, id);
+
+        let missing = change(Op::Insert, vec![], vec![column("id", Value::Uuid(id))]);
+        assert!(matches!(
+            Tenant::from_insert(&missing),
+            Err(ReplicationError::Mapping(_))
+        ));
+        let mistyped = change(
+            Op::Insert,
+            vec![],
+            vec![
+                column("id", Value::Text(id.to_string())),
+                column("name", Value::Text("tenant".to_string())),
+                column("tags", Value::Array(vec![])),
+            ],
+        );
+        assert!(matches!(
+            Tenant::from_insert(&mistyped),
+            Err(ReplicationError::Mapping(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive_composite_key() -> Result<(), ReplicationError> {
+        let membership = Membership::from_insert(&change(
+            Op::Insert,
+            vec![],
+            vec![
+                column("tenant_id", Value::Int8(1)),
+                column("user_id", Value::Int4(2)),
+                column("role", Value::Text("admin".to_string())),
+            ],
+        ))?;
+        assert_eq!(membership.key(), (1, 2));
+
+        // the key is read from the old row when the update changes it
+        let update = change(
+            Op::Update,
+            vec![
+                column("tenant_id", Value::Int8(1)),
+                column("user_id", Value::Int8(2)),
+            ],
+            vec![
+                column("tenant_id", Value::Int8(1)),
+                column("user_id", Value::Int8(3)),
+                column("role", Value::Text("admin".to_string())),
+            ],
+        );
+        assert_eq!(Membership::key_from_change(&update)?, (1, 2));
+
+        Ok(())
+    }
+}
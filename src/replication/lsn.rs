@@ -0,0 +1,144 @@
+use std::{
+    fmt,
+    ops::{Add, Sub},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// a position in the write-ahead log
+///
+/// printed and parsed in the textual `X/Y` form used by the server where `X` and `Y` are the high and
+/// low 32 bits in hexadecimal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn(u64);
+
+impl Lsn {
+    /// the invalid position `0/0` which lets the server choose where to start
+    pub const INVALID: Lsn = Lsn(0);
+
+    pub const fn new(lsn: u64) -> Self {
+        Self(lsn)
+    }
+
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// the number of bytes of wal between two positions regardless of their order
+    pub fn distance(&self, other: Lsn) -> u64 {
+        self.0.abs_diff(other.0)
+    }
+
+    /// the position `bytes` further, or `None` beyond the end of the wal
+    pub fn checked_add(self, bytes: u64) -> Option<Lsn> {
+        self.0.checked_add(bytes).map(Lsn)
+    }
+
+    /// the position `bytes` earlier, or `None` before its start
+    pub fn checked_sub(self, bytes: u64) -> Option<Lsn> {
+        self.0.checked_sub(bytes).map(Lsn)
+    }
+}
+
+impl From<u64> for Lsn {
+    fn from(lsn: u64) -> Self {
+        Self(lsn)
+    }
+}
+
+impl From<Lsn> for u64 {
+    fn from(lsn: Lsn) -> Self {
+        lsn.0
+    }
+}
+
+/// saturates at the end of the wal, see `checked_add`
+impl Add<u64> for Lsn {
+    type Output = Lsn;
+
+    fn add(self, bytes: u64) -> Lsn {
+        Lsn(self.0.saturating_add(bytes))
+    }
+}
+
+/// saturates at `Lsn::INVALID`, see `checked_sub`
+impl Sub<u64> for Lsn {
+    type Output = Lsn;
+
+    fn sub(self, bytes: u64) -> Lsn {
+        Lsn(self.0.saturating_sub(bytes))
+    }
+}
+
+impl fmt::Display for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid lsn: {0:?}")]
+pub struct ParseLsnError(String);
+
+impl FromStr for Lsn {
+    type Err = ParseLsnError;
+
+    fn from_str(s: &str) -> Result<Self, ParseLsnError> {
+        let (high, low) = s
+            .split_once('/')
+            .ok_or_else(|| ParseLsnError(s.to_string()))?;
+        let high = u32::from_str_radix(high, 16).map_err(|_| ParseLsnError(s.to_string()))?;
+        let low = u32::from_str_radix(low, 16).map_err(|_| ParseLsnError(s.to_string()))?;
+        Ok(Lsn(((high as u64) << 32) | low as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let lsn = "16/B374D848".parse::<Lsn>().unwrap();
+        assert_eq!(lsn.as_u64(), 0x16_B374_D848);
+        assert_eq!(lsn.to_string(), "16/B374D848");
+
+        assert_eq!("0/0".parse::<Lsn>().unwrap(), Lsn::INVALID);
+        assert_eq!(Lsn::from(u64::MAX).to_string(), "FFFFFFFF/FFFFFFFF");
+        assert_eq!(
+            "ffffffff/ffffffff".parse::<Lsn>().unwrap().as_u64(),
+            u64::MAX
+        );
+
+        assert!("16B374D848".parse::<Lsn>().is_err());
+        assert!("16/".parse::<Lsn>().is_err());
+        assert!("1/100000000".parse::<Lsn>().is_err());
+        assert!("G/0".parse::<Lsn>().is_err());
+    }
+
+    #[test]
+    fn test_ordering_and_arithmetic() {
+        let earlier = "0/FFFFFFFF".parse::<Lsn>().unwrap();
+        let later = earlier + 1;
+
+        assert!(earlier < later);
+        assert_eq!(later.to_string(), "1/0");
+        assert_eq!(later - 1, earlier);
+        assert_eq!(earlier.distance(later), 1);
+        assert_eq!(later.distance(earlier), 1);
+    }
+
+    #[test]
+    fn test_arithmetic_boundaries() {
+        let last = Lsn::new(u64::MAX);
+
+        assert_eq!(Lsn::INVALID - 1, Lsn::INVALID);
+        assert_eq!(Lsn::INVALID.checked_sub(1), None);
+        assert_eq!(Lsn::new(1).checked_sub(1), Some(Lsn::INVALID));
+
+        assert_eq!(last + 1, last);
+        assert_eq!(last.checked_add(1), None);
+        assert_eq!((last - 1).checked_add(1), Some(last));
+        assert_eq!(Lsn::INVALID.distance(last), u64::MAX);
+    }
+}
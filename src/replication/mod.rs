@@ -0,0 +1,511 @@
+pub mod decoderbufs {
+    include!(concat!(env!("OUT_DIR"), "/decoderbufs.rs"));
+}
+mod change;
+mod config;
+mod error;
+mod fanout;
+mod feedback;
+mod filter;
+mod from_change;
+mod lsn;
+mod plugin;
+mod prepared;
+mod protocol;
+mod slot;
+mod snapshot;
+mod spill;
+mod stream;
+mod streaming;
+mod subscription;
+mod supervisor;
+mod table_cache;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod value;
+
+use bytes::Bytes;
+pub use change::{Change, Column, Op, Truncate};
+pub use config::{ReplicationConfig, SslMode};
+pub use error::ReplicationError;
+pub use fanout::{Fanout, TransactionReceiver};
+use feedback::Feedback;
+pub use feedback::{Acker, Acknowledgements};
+pub use filter::Filter;
+#[doc(hidden)]
+pub use from_change::__private;
+pub use from_change::{FromChange, FromValue};
+use futures::{SinkExt, StreamExt};
+pub use logicaldecoding_derive::FromChange;
+pub use lsn::{Lsn, ParseLsnError};
+pub use plugin::{
+    DecoderbufsDecoder, OutputPlugin, PgOutputColumn, PgOutputDecoder, PgOutputMessage,
+    PgOutputRelation, PgOutputTuple, PgOutputValue, Plugin, PluginFactory, PluginMessage,
+    TestDecodingDecoder, Wal2JsonColumn, Wal2JsonDecoder, Wal2JsonMessage,
+};
+pub use prepared::PreparedTransactions;
+pub use protocol::{PrimaryKeepalive, ReplicationMessage, XLogData, XLogDataHeader};
+use slot::Slot;
+pub use snapshot::Snapshot;
+use spill::ChangeBuffer;
+pub use spill::Events;
+use std::{future::Future, mem, pin::Pin, sync::Arc};
+pub use stream::ReplicationStream;
+use streaming::StreamedTransactions;
+pub use subscription::{Notification, Predicate, Subscription, Subscriptions};
+pub use supervisor::{supervise_streaming_changes, LifecycleEvent};
+pub use table_cache::{TableCache, TableCacheUpdate};
+use tokio::{
+    sync::{broadcast, oneshot},
+    time::Interval,
+};
+use tokio_postgres::{Client, CopyBothDuplex, NoTls};
+use tracing::{debug, trace, warn};
+pub use value::Value;
+
+/// a committed transaction and its changes
+///
+/// transactions are fanned out to subscribers as `Arc<Transaction>` so delivering a transaction costs
+/// the same regardless of its size or the number of subscribers.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Transaction {
+    pub xid: u32,
+    /// the time of the commit, or of the prepare or rollback for two-phase transactions
+    pub commit_time: u64,
+    /// the end of the commit record which is acknowledged once the transaction is processed
+    pub commit_lsn: Lsn,
+    /// the XLogData header of the commit message
+    pub header: XLogDataHeader,
+    /// the changes, read back from disk while iterating if the transaction was spilled
+    pub events: Events,
+    pub kind: TransactionKind,
+}
+
+/// how the transaction delivered to consumers ended
+///
+/// slots decoding two-phase commits deliver the changes of a prepared transaction with its
+/// `Prepare`, before it is committed or rolled back, and a `CommitPrepared` or `RollbackPrepared`
+/// without changes once it is. every other transaction is a `Commit`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum TransactionKind {
+    #[default]
+    Commit,
+    Prepare {
+        gid: String,
+    },
+    CommitPrepared {
+        gid: String,
+    },
+    RollbackPrepared {
+        gid: String,
+    },
+}
+
+/// starts streaming changes
+///
+/// changes are only confirmed to the server as flushed once acknowledged by every consumer registered
+/// with `acknowledgements`
+pub async fn start_streaming_changes(
+    config: ReplicationConfig,
+    ready: oneshot::Sender<()>,
+    tx: broadcast::Sender<Arc<Transaction>>,
+    acknowledgements: Acknowledgements,
+) -> Result<(), ReplicationError> {
+    warn_unless_truncate_emitted(&config);
+    let session = connect(&config, &acknowledgements).await?;
+
+    // notify ready
+    ready.send(()).map_err(|_| ReplicationError::ConsumerGone)?;
+
+    ReplicationStream::new(config, session, acknowledgements)
+        .broadcast(tx)
+        .await
+}
+
+/// starts streaming changes like `start_streaming_changes` after bootstrapping from a snapshot
+///
+/// the slot is created with `EXPORT_SNAPSHOT` and `load` is called with the exported snapshot before
+/// streaming starts from the slot's `consistent_point`. tables loaded by `load`, e.g. with
+/// `TableCache::load`, therefore see every change committed before the first streamed transaction
+/// and none after it. the slot must not exist yet and `start_lsn` must not be configured.
+pub async fn start_streaming_changes_from_snapshot<F, Fut>(
+    config: ReplicationConfig,
+    ready: oneshot::Sender<()>,
+    tx: broadcast::Sender<Arc<Transaction>>,
+    acknowledgements: Acknowledgements,
+    load: F,
+) -> Result<(), ReplicationError>
+where
+    F: FnOnce(Snapshot) -> Fut,
+    Fut: Future<Output = Result<(), ReplicationError>>,
+{
+    if config.start_lsn.is_some() {
+        return Err(ReplicationError::Config(
+            "a start_lsn cannot be combined with a snapshot".to_string(),
+        ));
+    }
+
+    warn_unless_truncate_emitted(&config);
+    let (client, plugin, slot) = create_slot(&config, true).await?;
+    let snapshot_name = slot
+        .snapshot_name
+        .clone()
+        .ok_or_else(|| ReplicationError::Protocol("slot created without a snapshot".to_string()))?;
+
+    // the exported snapshot stays valid as long as the replication connection is idle
+    let snapshot = Snapshot::import(&config, snapshot_name, slot.start_lsn).await?;
+    load(snapshot).await?;
+
+    let session = start_replication(client, &config, plugin, slot, &acknowledgements).await?;
+
+    // notify ready
+    ready.send(()).map_err(|_| ReplicationError::ConsumerGone)?;
+
+    ReplicationStream::new(config, session, acknowledgements)
+        .broadcast(tx)
+        .await
+}
+
+/// warns once per stream rather than per connection if the configured plugin skips truncates
+pub(crate) fn warn_unless_truncate_emitted(config: &ReplicationConfig) {
+    if !config.plugin.emits_truncate() {
+        warn!(
+            "{} does not decode TRUNCATE so consumers silently diverge from truncated tables",
+            config.plugin.name()
+        );
+    }
+}
+
+/// warns if no consumer gates the positions confirmed to the server, which then never advance
+pub(crate) fn warn_unless_acknowledged(acknowledgements: &Acknowledgements) {
+    if acknowledgements.acknowledged().is_none() {
+        warn!(
+            "no consumer acknowledges transactions so the slot retains WAL until one is registered"
+        );
+    }
+}
+
+/// an established replication connection streaming from a slot
+pub(crate) struct Session {
+    // the client has to outlive the duplex stream it issued
+    _client: Client,
+    duplex_stream: Pin<Box<CopyBothDuplex<Bytes>>>,
+    feedback: Feedback,
+    plugin: Box<dyn OutputPlugin>,
+    status_interval: Interval,
+    streamed: StreamedTransactions,
+    pub slot: Slot,
+}
+
+/// connects, creates or reuses the slot and issues `START_REPLICATION`
+pub(crate) async fn connect(
+    config: &ReplicationConfig,
+    acknowledgements: &Acknowledgements,
+) -> Result<Session, ReplicationError> {
+    let (client, plugin, slot) = create_slot(config, false).await?;
+    start_replication(client, config, plugin, slot, acknowledgements).await
+}
+
+/// connects and creates or reuses the slot
+async fn create_slot(
+    config: &ReplicationConfig,
+    export_snapshot: bool,
+) -> Result<(Client, Box<dyn OutputPlugin>, Slot), ReplicationError> {
+    debug!(
+        "Connecting to {}:{}/{} as {}",
+        config.host, config.port, config.dbname, config.user
+    );
+
+    // connect to the database
+    let (client, connection) =
+        tokio_postgres::connect(&config.connection_string(true), NoTls).await?;
+
+    // the connection object performs the actual communication with the database, so spawn it off to run on its own
+    tokio::spawn(connection);
+
+    let plugin = config.plugin.output_plugin();
+    let slot =
+        slot::create_or_reuse_slot(&client, config, plugin.as_ref(), export_snapshot).await?;
+    Ok((client, plugin, slot))
+}
+
+/// issues `START_REPLICATION` on a connection with a ready slot
+async fn start_replication(
+    client: Client,
+    config: &ReplicationConfig,
+    plugin: Box<dyn OutputPlugin>,
+    slot: Slot,
+    acknowledgements: &Acknowledgements,
+) -> Result<Session, ReplicationError> {
+    let mut query = format!(
+        "START_REPLICATION SLOT {} LOGICAL {}",
+        slot.name, slot.start_lsn
+    );
+    if let Some(options) = plugin.start_replication_options() {
+        query = format!("{} {}", query, options);
+    }
+    let duplex_stream = client.copy_both_simple::<Bytes>(&query).await?;
+    let mut duplex_stream = Box::pin(duplex_stream);
+    let mut feedback = Feedback::default();
+
+    // send a status update to ensure connection is functioning
+    duplex_stream
+        .send(feedback.status_update(acknowledgements.acknowledged(), false))
+        .await?;
+
+    Ok(Session {
+        _client: client,
+        duplex_stream,
+        feedback,
+        plugin,
+        status_interval: tokio::time::interval(config.status_interval),
+        streamed: StreamedTransactions::default(),
+        slot,
+    })
+}
+
+/// streams changes from an established session until the server ends the stream or an error occurs
+pub(crate) async fn stream_changes(
+    session: &mut Session,
+    config: &ReplicationConfig,
+    tx: &broadcast::Sender<Arc<Transaction>>,
+    acknowledgements: &Acknowledgements,
+) -> Result<(), ReplicationError> {
+    while let Some(transaction) = next_transaction(session, config, acknowledgements).await? {
+        tx.send(Arc::new(transaction))
+            .map_err(|_| ReplicationError::ConsumerGone)?;
+    }
+    Ok(())
+}
+
+/// completes the transaction begun by the last `Begin` once its commit or prepare arrives
+fn end_transaction(
+    transaction: Option<Transaction>,
+    changes: ChangeBuffer,
+    header: XLogDataHeader,
+    commit_time: Option<u64>,
+    kind: TransactionKind,
+) -> Result<Transaction, ReplicationError> {
+    let mut transaction = transaction
+        .ok_or_else(|| ReplicationError::Protocol(format!("{:?} without BEGIN", kind)))?;
+    // the commit message is written at the end of the commit record
+    transaction.commit_lsn = header.wal_end;
+    transaction.header = header;
+    // plugins such as test_decoding only know the commit time once committed
+    if let Some(commit_time) = commit_time {
+        transaction.commit_time = commit_time;
+    }
+    transaction.events = changes.into_events()?;
+    transaction.kind = kind;
+    Ok(transaction)
+}
+
+/// receives the next committed transaction, or `None` once the server ends the stream
+///
+/// standby status updates are only sent while waiting for a transaction.
+pub(crate) async fn next_transaction(
+    session: &mut Session,
+    config: &ReplicationConfig,
+    acknowledgements: &Acknowledgements,
+) -> Result<Option<Transaction>, ReplicationError> {
+    let Session {
+        duplex_stream: duplex_stream_pin,
+        feedback,
+        plugin,
+        status_interval,
+        streamed,
+        ..
+    } = session;
+
+    let mut transaction = None;
+    // the changes of the current transaction, spilled to disk beyond the configured threshold
+    let mut changes = ChangeBuffer::default();
+    // whether the filter dropped changes of the current transaction
+    let mut filtered = false;
+    loop {
+        let event = tokio::select! {
+            event = duplex_stream_pin.next() => event,
+            _ = status_interval.tick() => {
+                let status = feedback.status_update(acknowledgements.acknowledged(), false);
+                trace!("Sending periodic status update:{:x?}", status);
+                duplex_stream_pin.send(status).await?;
+                continue;
+            }
+        };
+
+        let event = match event {
+            None => return Ok(None),
+            Some(Err(err)) => return Err(err.into()),
+            Some(Ok(event)) => ReplicationMessage::parse(event)?,
+        };
+
+        match event {
+            // type: XLogData (WAL data, ie. change of data in db)
+            ReplicationMessage::XLogData(XLogData { header, data }) => {
+                feedback.received(header.wal_end);
+
+                let message = match plugin.decode(header.wal_start, data)? {
+                    Some(message) => message,
+                    None => continue,
+                };
+                debug!("Got XLogData/data-change event: {:?}", message);
+
+                let transaction = match message {
+                    PluginMessage::Begin { xid, commit_time } => {
+                        feedback.begin();
+                        filtered = false;
+                        changes = ChangeBuffer::new(
+                            config.spill_threshold,
+                            config.spill_directory.clone(),
+                        );
+                        transaction = Some(Transaction {
+                            xid: xid.unwrap_or_default(),
+                            commit_time: commit_time.unwrap_or_default(),
+                            commit_lsn: Lsn::INVALID,
+                            header,
+                            events: Events::default(),
+                            kind: TransactionKind::Commit,
+                        });
+                        continue;
+                    }
+                    PluginMessage::Commit { commit_time } => {
+                        let transaction = end_transaction(
+                            transaction.take(),
+                            mem::take(&mut changes),
+                            header,
+                            commit_time,
+                            TransactionKind::Commit,
+                        )?;
+                        if filtered && transaction.events.is_empty() {
+                            trace!("Skipping filtered transaction {}", transaction.xid);
+                            feedback.skip();
+                            continue;
+                        }
+                        transaction
+                    }
+                    // prepared transactions are delivered even if filtered out entirely so their
+                    // COMMIT PREPARED or ROLLBACK PREPARED can be matched
+                    PluginMessage::Prepare { gid, prepare_time } => end_transaction(
+                        transaction.take(),
+                        mem::take(&mut changes),
+                        header,
+                        prepare_time,
+                        TransactionKind::Prepare { gid },
+                    )?,
+                    PluginMessage::CommitPrepared {
+                        xid,
+                        gid,
+                        commit_time,
+                    } => Transaction {
+                        xid,
+                        commit_time: commit_time.unwrap_or_default(),
+                        commit_lsn: header.wal_end,
+                        header,
+                        events: Events::default(),
+                        kind: TransactionKind::CommitPrepared { gid },
+                    },
+                    PluginMessage::RollbackPrepared {
+                        xid,
+                        gid,
+                        rollback_time,
+                    } => Transaction {
+                        xid,
+                        commit_time: rollback_time.unwrap_or_default(),
+                        commit_lsn: header.wal_end,
+                        header,
+                        events: Events::default(),
+                        kind: TransactionKind::RollbackPrepared { gid },
+                    },
+                    PluginMessage::Change(change) => {
+                        let transaction = transaction.as_ref().ok_or_else(|| {
+                            ReplicationError::Protocol(
+                                "change outside of a transaction".to_string(),
+                            )
+                        })?;
+                        for change in change.per_table() {
+                            match config.filter.apply(change) {
+                                Some(change) => changes.push(transaction.xid, change)?,
+                                None => filtered = true,
+                            }
+                        }
+                        continue;
+                    }
+                    PluginMessage::StreamStart { xid } => {
+                        streamed.start(xid, config)?;
+                        feedback.streaming(true);
+                        continue;
+                    }
+                    PluginMessage::StreamStop => {
+                        streamed.stop();
+                        continue;
+                    }
+                    PluginMessage::StreamChange { xid, change } => {
+                        for change in change.per_table() {
+                            streamed.push(xid, change, &config.filter)?;
+                        }
+                        continue;
+                    }
+                    PluginMessage::StreamAbort { xid, subxid } => {
+                        streamed.abort(xid, subxid);
+                        feedback.streaming(streamed.in_progress());
+                        continue;
+                    }
+                    PluginMessage::StreamCommit { xid, commit_time } => {
+                        let (events, filtered) = streamed.commit(xid)?;
+                        feedback.streaming(streamed.in_progress());
+                        if filtered && events.is_empty() {
+                            trace!("Skipping filtered streamed transaction {}", xid);
+                            continue;
+                        }
+                        Transaction {
+                            xid,
+                            commit_time: commit_time.unwrap_or_default(),
+                            commit_lsn: header.wal_end,
+                            header,
+                            events,
+                            kind: TransactionKind::Commit,
+                        }
+                    }
+                    PluginMessage::StreamPrepare {
+                        xid,
+                        gid,
+                        prepare_time,
+                    } => {
+                        let (events, _) = streamed.commit(xid)?;
+                        feedback.streaming(streamed.in_progress());
+                        Transaction {
+                            xid,
+                            commit_time: prepare_time.unwrap_or_default(),
+                            commit_lsn: header.wal_end,
+                            header,
+                            events,
+                            kind: TransactionKind::Prepare { gid },
+                        }
+                    }
+                };
+
+                feedback.commit(transaction.commit_lsn);
+                debug!("{:?}", &transaction);
+                return Ok(Some(transaction));
+            }
+            // type: keepalive message
+            ReplicationMessage::PrimaryKeepalive(keepalive) => {
+                feedback.received(keepalive.wal_end);
+
+                trace!("Got keepalive message:{:?}", keepalive);
+                if keepalive.reply_requested {
+                    let status = feedback.status_update(acknowledgements.acknowledged(), false);
+                    trace!(
+                        "Trying to send response to keepalive message/warning!:{:x?}",
+                        status
+                    );
+
+                    duplex_stream_pin.send(status.clone()).await?;
+
+                    trace!("Sent response to keepalive message/warning!:{:x?}", status);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use super::{unquote_qualified_name, OutputPlugin, PluginMessage};
+use crate::replication::{
+    decoderbufs::{DatumMessage, Op as RowOp, RowMessage},
+    Change, Column, Lsn, Op, ReplicationError, Value,
+};
+use bytes::Bytes;
+use prost::Message;
+
+/// decodes the `RowMessage` protobufs emitted by the `decoderbufs` extension
+#[derive(Debug, Default)]
+pub struct DecoderbufsDecoder;
+
+impl OutputPlugin for DecoderbufsDecoder {
+    fn name(&self) -> &str {
+        "decoderbufs"
+    }
+
+    fn decode(&mut self, lsn: Lsn, data: Bytes) -> Result<Option<PluginMessage>, ReplicationError> {
+        let row_message = RowMessage::decode(data)?;
+
+        let op = match row_message.op.and_then(RowOp::from_i32) {
+            Some(RowOp::Begin) => {
+                return Ok(Some(PluginMessage::Begin {
+                    xid: row_message.transaction_id,
+                    commit_time: row_message.commit_time,
+                }))
+            }
+            Some(RowOp::Commit) => {
+                return Ok(Some(PluginMessage::Commit {
+                    commit_time: row_message.commit_time,
+                }))
+            }
+            Some(RowOp::Insert) => Op::Insert,
+            Some(RowOp::Update) => Op::Update,
+            Some(RowOp::Delete) => Op::Delete,
+            Some(RowOp::Unknown) | None => {
+                return Err(ReplicationError::Plugin(format!(
+                    "row message without op: {:?}",
+                    row_message
+                )))
+            }
+        };
+
+        Ok(Some(PluginMessage::Change(Change {
+            // quoted by the extension where necessary, unlike the other plugins
+            table: unquote_qualified_name(row_message.table())?,
+            op,
+            old: columns(row_message.old_tuple)?,
+            new: columns(row_message.new_tuple)?,
+            lsn,
+            truncate: None,
+        })))
+    }
+}
+
+fn columns(datums: Vec<DatumMessage>) -> Result<Vec<Column>, ReplicationError> {
+    datums
+        .into_iter()
+        .map(|datum| {
+            Ok(Column {
+                name: datum.column_name().to_string(),
+                type_oid: datum.column_type.map(|type_oid| type_oid as u32),
+                value: Value::try_from(datum)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::decoderbufs::datum_message::Datum;
+
+    #[test]
+    fn test_decode() -> Result<(), ReplicationError> {
+        let mut decoder = DecoderbufsDecoder;
+
+        let begin = RowMessage {
+            transaction_id: Some(733),
+            commit_time: Some(946_684_801_000_000),
+            op: Some(RowOp::Begin as i32),
+            ..Default::default()
+        };
+        assert_eq!(
+            decoder.decode(Lsn::new(0x0100), begin.encode_to_vec().into())?,
+            Some(PluginMessage::Begin {
+                xid: Some(733),
+                commit_time: Some(946_684_801_000_000),
+            })
+        );
+
+        let update = RowMessage {
+            transaction_id: Some(733),
+            table: Some("public.tenants".to_string()),
+            op: Some(RowOp::Update as i32),
+            new_tuple: vec![
+                DatumMessage {
+                    column_name: Some("id".to_string()),
+                    column_type: Some(23),
+                    datum: Some(Datum::DatumInt32(1)),
+                },
+                DatumMessage {
+                    column_name: Some("body".to_string()),
+                    column_type: Some(25),
+                    datum: Some(Datum::DatumMissing(true)),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            decoder.decode(Lsn::new(0x0110), update.encode_to_vec().into())?,
+            Some(PluginMessage::Change(Change {
+                table: "public.tenants".to_string(),
+                op: Op::Update,
+                old: vec![],
+                new: vec![
+                    Column {
+                        name: "id".to_string(),
+                        type_oid: Some(23),
+                        value: Value::Int4(1),
+                    },
+                    Column {
+                        name: "body".to_string(),
+                        type_oid: Some(25),
+                        value: Value::UnchangedToast,
+                    },
+                ],
+                lsn: Lsn::new(0x0110),
+                truncate: None,
+            }))
+        );
+
+        // tables are named like by every other plugin
+        let quoted = RowMessage {
+            table: Some("\"My Schema\".\"Tenant Events\"".to_string()),
+            op: Some(RowOp::Delete as i32),
+            ..Default::default()
+        };
+        match decoder.decode(Lsn::new(0x0118), quoted.encode_to_vec().into())? {
+            Some(PluginMessage::Change(change)) => {
+                assert_eq!(change.table, "My Schema.Tenant Events")
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+
+        let without_op = RowMessage::default();
+        assert!(matches!(
+            decoder.decode(Lsn::new(0x0120), without_op.encode_to_vec().into()),
+            Err(ReplicationError::Plugin(_))
+        ));
+
+        Ok(())
+    }
+}
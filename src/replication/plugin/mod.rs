@@ -0,0 +1,422 @@
+mod decoderbufs;
+mod pgoutput;
+mod test_decoding;
+mod wal2json;
+
+use super::{Change, Lsn, ReplicationError};
+use bytes::Bytes;
+use chrono::DateTime;
+pub use decoderbufs::DecoderbufsDecoder;
+pub use pgoutput::{
+    PgOutputColumn, PgOutputDecoder, PgOutputMessage, PgOutputRelation, PgOutputTuple,
+    PgOutputValue,
+};
+use std::{fmt, sync::Arc};
+pub use test_decoding::TestDecodingDecoder;
+pub use wal2json::{Wal2JsonColumn, Wal2JsonDecoder, Wal2JsonMessage};
+
+/// a message decoded by an output plugin
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginMessage {
+    /// the start of a transaction. plugins which only know the commit time once committed leave
+    /// it out here.
+    Begin {
+        xid: Option<u32>,
+        commit_time: Option<u64>,
+    },
+    Commit {
+        commit_time: Option<u64>,
+    },
+    /// the end of a transaction prepared for two-phase commit as `gid`, which began like any other
+    Prepare {
+        gid: String,
+        prepare_time: Option<u64>,
+    },
+    /// the commit of the prepared transaction `gid`
+    CommitPrepared {
+        xid: u32,
+        gid: String,
+        commit_time: Option<u64>,
+    },
+    /// the rollback of the prepared transaction `gid`
+    RollbackPrepared {
+        xid: u32,
+        gid: String,
+        rollback_time: Option<u64>,
+    },
+    Change(Change),
+    /// the start of a block of changes of the in-progress transaction `xid`, which is streamed
+    /// before it commits
+    StreamStart {
+        xid: u32,
+    },
+    /// the end of a block of changes of a streamed transaction
+    StreamStop,
+    /// a change within a streamed block made by the transaction or subtransaction `xid`
+    StreamChange {
+        xid: u32,
+        change: Change,
+    },
+    /// the commit of the streamed transaction `xid`
+    StreamCommit {
+        xid: u32,
+        commit_time: Option<u64>,
+    },
+    /// the prepare of the streamed transaction `xid` for two-phase commit as `gid`
+    StreamPrepare {
+        xid: u32,
+        gid: String,
+        prepare_time: Option<u64>,
+    },
+    /// the rollback of the subtransaction `subxid` of the streamed transaction `xid`, or of the
+    /// whole transaction if they are the same
+    StreamAbort {
+        xid: u32,
+        subxid: u32,
+    },
+}
+
+/// the server side of a logical decoding output plugin: how slots are created and streamed from
+/// and how the data it sends is decoded
+///
+/// implementations may keep state between messages, such as the relations described by `pgoutput`,
+/// so a fresh instance is created for each replication connection.
+pub trait OutputPlugin: fmt::Debug + Send + Sync {
+    /// the name the plugin is installed under on the server
+    fn name(&self) -> &str;
+
+    /// whether `TRUNCATE` is decoded into `Op::Truncate` changes. the server skips truncates for
+    /// plugins which do not handle them, so a warning is logged when such a plugin is used.
+    fn emits_truncate(&self) -> bool {
+        false
+    }
+
+    /// options appended to `CREATE_REPLICATION_SLOT`
+    fn create_slot_options(&self) -> Option<String> {
+        None
+    }
+
+    /// the plugin options appended to `START_REPLICATION`
+    fn start_replication_options(&self) -> Option<String> {
+        None
+    }
+
+    /// decodes the payload of a single XLogData message starting at `lsn`. messages which only carry
+    /// plugin state such as relation descriptions decode to `None`
+    fn decode(&mut self, lsn: Lsn, data: Bytes) -> Result<Option<PluginMessage>, ReplicationError>;
+}
+
+/// the logical decoding output plugin a slot is created with
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Plugin {
+    /// the `decoderbufs` extension emitting `RowMessage` protobufs
+    #[default]
+    Decoderbufs,
+    /// the built-in `pgoutput` plugin streaming the tables of the given publications
+    Pgoutput {
+        publication_names: Vec<String>,
+        /// whether large in-progress transactions are streamed before they commit
+        streaming: bool,
+        /// whether prepared transactions are decoded when prepared
+        two_phase: bool,
+    },
+    /// the `wal2json` extension emitting one JSON document per change (format version 2)
+    Wal2json,
+    /// the `test_decoding` plugin shipped with the server whose textual output is meant for debugging
+    TestDecoding,
+    /// an `OutputPlugin` implemented outside of this crate, see `Plugin::custom`
+    Custom(PluginFactory),
+}
+
+/// creates the instances of a custom `OutputPlugin`
+///
+/// factories are only equal to their clones.
+#[derive(Clone)]
+pub struct PluginFactory {
+    name: String,
+    factory: Arc<dyn Fn() -> Box<dyn OutputPlugin> + Send + Sync>,
+}
+
+impl fmt::Debug for PluginFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PluginFactory")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for PluginFactory {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.factory) as *const () == Arc::as_ptr(&other.factory) as *const ()
+    }
+}
+
+impl Eq for PluginFactory {}
+
+impl Plugin {
+    /// the built-in `pgoutput` plugin streaming the tables of a single publication
+    pub fn pgoutput(publication_name: impl Into<String>) -> Self {
+        Plugin::Pgoutput {
+            publication_names: vec![publication_name.into()],
+            streaming: false,
+            two_phase: false,
+        }
+    }
+
+    /// lets the server stream large in-progress transactions of the `pgoutput` plugin before they
+    /// commit rather than decoding them in its own memory first (protocol version 2, Postgres 14+)
+    ///
+    /// their changes are still only delivered once committed, buffered until then and spilled to
+    /// disk beyond the `spill_threshold`, and those of aborted (sub)transactions are discarded.
+    /// consumers such as `TableCache` and `Subscriptions` must never see changes which may still
+    /// roll back, so the streamed blocks are not forwarded as they arrive. this moves the memory
+    /// used by large transactions from the server to the client, which should therefore configure
+    /// a `spill_threshold`. other plugins are unaffected.
+    pub fn streaming(self, streaming: bool) -> Self {
+        match self {
+            Plugin::Pgoutput {
+                publication_names,
+                two_phase,
+                ..
+            } => Plugin::Pgoutput {
+                publication_names,
+                streaming,
+                two_phase,
+            },
+            plugin => plugin,
+        }
+    }
+
+    /// decodes prepared transactions of the `pgoutput` plugin when they are prepared rather than once
+    /// committed (protocol version 3, Postgres 15+)
+    ///
+    /// their changes are delivered in a `TransactionKind::Prepare` followed by a `CommitPrepared` or
+    /// `RollbackPrepared` once the transaction ends. two-phase decoding is a property of the slot so
+    /// it only takes effect for slots created with it. other plugins are unaffected.
+    pub fn two_phase(self, two_phase: bool) -> Self {
+        match self {
+            Plugin::Pgoutput {
+                publication_names,
+                streaming,
+                ..
+            } => Plugin::Pgoutput {
+                publication_names,
+                streaming,
+                two_phase,
+            },
+            plugin => plugin,
+        }
+    }
+
+    /// a custom output plugin, `factory` creating a fresh instance for each replication connection
+    pub fn custom<F>(factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn OutputPlugin> + Send + Sync + 'static,
+    {
+        Plugin::Custom(PluginFactory {
+            name: factory().name().to_string(),
+            factory: Arc::new(factory),
+        })
+    }
+
+    /// whether `TRUNCATE` is decoded, see `OutputPlugin::emits_truncate`. consumers such as caches
+    /// silently diverge from truncated tables otherwise.
+    pub fn emits_truncate(&self) -> bool {
+        self.output_plugin().emits_truncate()
+    }
+
+    /// the name the plugin is installed under on the server
+    pub fn name(&self) -> &str {
+        match self {
+            Plugin::Decoderbufs => "decoderbufs",
+            Plugin::Pgoutput { .. } => "pgoutput",
+            Plugin::Wal2json => "wal2json",
+            Plugin::TestDecoding => "test_decoding",
+            Plugin::Custom(factory) => &factory.name,
+        }
+    }
+
+    /// creates a fresh instance of the plugin for a replication connection
+    pub fn output_plugin(&self) -> Box<dyn OutputPlugin> {
+        match self {
+            Plugin::Decoderbufs => Box::new(DecoderbufsDecoder),
+            Plugin::Pgoutput {
+                publication_names,
+                streaming,
+                two_phase,
+            } => Box::new(
+                PgOutputDecoder::new(publication_names.clone())
+                    .streaming(*streaming)
+                    .two_phase(*two_phase),
+            ),
+            Plugin::Wal2json => Box::new(Wal2JsonDecoder),
+            Plugin::TestDecoding => Box::new(TestDecodingDecoder),
+            Plugin::Custom(factory) => (factory.factory)(),
+        }
+    }
+}
+
+/// parses a timestamptz as printed by the server into microseconds since the unix epoch
+fn parse_timestamp(timestamp: &str) -> Result<u64, ReplicationError> {
+    DateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f%#z")
+        .map(|timestamp| timestamp.timestamp_micros() as u64)
+        .map_err(|err| {
+            ReplicationError::Plugin(format!("invalid timestamp {:?}: {}", timestamp, err))
+        })
+}
+
+/// the unquoted `schema.table` form every plugin names tables by, from a name quoted like the
+/// server's `quote_qualified_identifier`, e.g. `"My Schema".tenants` becomes `My Schema.tenants`
+fn unquote_qualified_name(name: &str) -> Result<String, ReplicationError> {
+    let invalid = || ReplicationError::Plugin(format!("invalid qualified table name {:?}", name));
+    let mut unquoted = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    let mut parts = 0;
+    loop {
+        if chars.next_if_eq(&'"').is_some() {
+            // quotes inside a quoted identifier are doubled
+            loop {
+                match chars.next().ok_or_else(invalid)? {
+                    '"' if chars.next_if_eq(&'"').is_none() => break,
+                    char => unquoted.push(char),
+                }
+            }
+        } else {
+            while let Some(char) = chars.next_if(|char| *char != '.') {
+                unquoted.push(char);
+            }
+        }
+        parts += 1;
+        match chars.next() {
+            Some('.') => unquoted.push('.'),
+            None if parts == 2 => return Ok(unquoted),
+            _ => return Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unquote_qualified_name() {
+        for (name, unquoted) in [
+            ("public.tenants", "public.tenants"),
+            ("\"public\".\"tenants\"", "public.tenants"),
+            ("\"My Schema\".\"a.\"\"b\"\"\"", "My Schema.a.\"b\""),
+        ] {
+            assert_eq!(unquote_qualified_name(name).unwrap(), unquoted);
+        }
+        for name in [
+            "tenants",
+            "public.tenants.audit",
+            "\"public.tenants",
+            "\"public\"x.tenants",
+        ] {
+            assert!(unquote_qualified_name(name).is_err());
+        }
+    }
+
+    #[test]
+    fn test_custom() {
+        #[derive(Debug)]
+        struct Custom;
+
+        impl OutputPlugin for Custom {
+            fn name(&self) -> &str {
+                "custom"
+            }
+
+            fn start_replication_options(&self) -> Option<String> {
+                Some("(\"option\" '1')".to_string())
+            }
+
+            fn decode(
+                &mut self,
+                _: Lsn,
+                _: Bytes,
+            ) -> Result<Option<PluginMessage>, ReplicationError> {
+                Ok(None)
+            }
+        }
+
+        let plugin = Plugin::custom(|| Box::new(Custom));
+        assert_eq!(plugin.name(), "custom");
+        assert_eq!(
+            plugin.output_plugin().start_replication_options().unwrap(),
+            "(\"option\" '1')"
+        );
+        assert_eq!(plugin.clone(), plugin);
+        assert!(!plugin.emits_truncate());
+        assert_ne!(Plugin::custom(|| Box::new(Custom)), plugin);
+    }
+
+    #[test]
+    fn test_start_replication_options() {
+        for plugin in [
+            Plugin::Decoderbufs,
+            Plugin::pgoutput("tenants"),
+            Plugin::Wal2json,
+            Plugin::TestDecoding,
+        ] {
+            assert_eq!(plugin.output_plugin().name(), plugin.name());
+            assert_eq!(plugin.emits_truncate(), plugin != Plugin::Decoderbufs);
+        }
+
+        assert_eq!(
+            Plugin::Decoderbufs
+                .output_plugin()
+                .start_replication_options(),
+            None
+        );
+        assert_eq!(
+            Plugin::Pgoutput {
+                publication_names: vec!["tenants".to_string(), "it's \"quoted\"".to_string()],
+                streaming: false,
+                two_phase: false,
+            }
+            .output_plugin()
+            .start_replication_options()
+            .unwrap(),
+            "(\"proto_version\" '1', \"publication_names\" '\"tenants\",\"it''s \"\"quoted\"\"\"')"
+        );
+        assert_eq!(
+            Plugin::pgoutput("tenants")
+                .streaming(true)
+                .output_plugin()
+                .start_replication_options()
+                .unwrap(),
+            "(\"proto_version\" '2', \"publication_names\" '\"tenants\"', \"streaming\" 'on')"
+        );
+        let two_phase = Plugin::pgoutput("tenants")
+            .streaming(true)
+            .two_phase(true)
+            .output_plugin();
+        assert_eq!(
+            two_phase.start_replication_options().unwrap(),
+            "(\"proto_version\" '3', \"publication_names\" '\"tenants\"', \"streaming\" 'on', \"two_phase\" 'on')"
+        );
+        assert_eq!(two_phase.create_slot_options().unwrap(), "TWO_PHASE");
+        assert_eq!(
+            Plugin::pgoutput("tenants")
+                .output_plugin()
+                .create_slot_options(),
+            None
+        );
+        assert_eq!(
+            Plugin::Wal2json
+                .output_plugin()
+                .start_replication_options()
+                .unwrap(),
+            "(\"format-version\" '2', \"include-xids\" '1', \"include-timestamp\" '1', \"include-type-oids\" '1', \"numeric-data-types-as-string\" '1')"
+        );
+        assert_eq!(
+            Plugin::TestDecoding
+                .output_plugin()
+                .start_replication_options()
+                .unwrap(),
+            "(\"include-xids\" '1', \"include-timestamp\" '1', \"skip-empty-xacts\" '1')"
+        );
+    }
+}
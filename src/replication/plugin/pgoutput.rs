@@ -0,0 +1,1125 @@
+use super::{OutputPlugin, PluginMessage};
+use crate::replication::{
+    protocol::from_pg_timestamp, Change, Column, Lsn, Op, ReplicationError, Truncate, Value,
+};
+use bytes::{Buf, Bytes};
+use std::{collections::HashMap, time::UNIX_EPOCH};
+use tracing::debug;
+
+/// the option bits of a `Truncate` message
+const TRUNCATE_CASCADE: u8 = 1;
+const TRUNCATE_RESTART_IDENTITY: u8 = 2;
+
+/// a column of a relation as described by a `Relation` message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgOutputColumn {
+    /// whether the column is part of the replica identity
+    pub key: bool,
+    pub name: String,
+    pub type_oid: u32,
+    pub type_modifier: i32,
+}
+
+/// a table description sent before the first change to it in each session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgOutputRelation {
+    pub id: u32,
+    pub namespace: String,
+    pub name: String,
+    pub replica_identity: u8,
+    pub columns: Vec<PgOutputColumn>,
+}
+
+/// a single column value of a tuple
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgOutputValue {
+    Null,
+    /// a TOASTed value which was not changed and is therefore not sent
+    UnchangedToast,
+    Text(Bytes),
+    Binary(Bytes),
+}
+
+pub type PgOutputTuple = Vec<PgOutputValue>;
+
+/// a message of the `pgoutput` logical replication protocol (version 1, 2 with streaming or 3 with
+/// two-phase commit)
+///
+/// see here for format details: https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgOutputMessage {
+    Begin {
+        final_lsn: Lsn,
+        commit_time: i64,
+        xid: u32,
+    },
+    Commit {
+        flags: u8,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: i64,
+    },
+    Origin {
+        commit_lsn: Lsn,
+        name: String,
+    },
+    Relation(PgOutputRelation),
+    Type {
+        id: u32,
+        namespace: String,
+        name: String,
+    },
+    Insert {
+        relation_id: u32,
+        new: PgOutputTuple,
+    },
+    Update {
+        relation_id: u32,
+        /// the replica identity ('K') or full ('O') old tuple if the server sent one
+        old: Option<PgOutputTuple>,
+        new: PgOutputTuple,
+    },
+    Delete {
+        relation_id: u32,
+        old: PgOutputTuple,
+    },
+    Truncate {
+        options: u8,
+        relation_ids: Vec<u32>,
+    },
+    Message {
+        transactional: bool,
+        lsn: Lsn,
+        prefix: String,
+        content: Bytes,
+    },
+    StreamStart {
+        xid: u32,
+        first_segment: bool,
+    },
+    StreamStop,
+    StreamCommit {
+        xid: u32,
+        flags: u8,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: i64,
+    },
+    StreamAbort {
+        xid: u32,
+        subxid: u32,
+    },
+    BeginPrepare {
+        prepare_lsn: Lsn,
+        end_lsn: Lsn,
+        prepare_time: i64,
+        xid: u32,
+        gid: String,
+    },
+    Prepare {
+        flags: u8,
+        prepare_lsn: Lsn,
+        end_lsn: Lsn,
+        prepare_time: i64,
+        xid: u32,
+        gid: String,
+    },
+    CommitPrepared {
+        flags: u8,
+        commit_lsn: Lsn,
+        end_lsn: Lsn,
+        commit_time: i64,
+        xid: u32,
+        gid: String,
+    },
+    RollbackPrepared {
+        flags: u8,
+        prepare_end_lsn: Lsn,
+        rollback_end_lsn: Lsn,
+        prepare_time: i64,
+        rollback_time: i64,
+        xid: u32,
+        gid: String,
+    },
+    StreamPrepare {
+        flags: u8,
+        prepare_lsn: Lsn,
+        end_lsn: Lsn,
+        prepare_time: i64,
+        xid: u32,
+        gid: String,
+    },
+}
+
+impl PgOutputMessage {
+    pub fn parse(data: Bytes) -> Result<Self, ReplicationError> {
+        Ok(Self::parse_streamed(data, false)?.1)
+    }
+
+    /// parses a message which is part of a streamed block if `in_stream`. such messages are
+    /// prefixed with the xid of the (sub)transaction they belong to, which is returned alongside.
+    pub fn parse_streamed(
+        data: Bytes,
+        in_stream: bool,
+    ) -> Result<(Option<u32>, Self), ReplicationError> {
+        let mut reader = Reader(data);
+
+        let tag = reader.u8()?;
+        let xid = match tag {
+            b'R' | b'Y' | b'I' | b'U' | b'D' | b'T' | b'M' if in_stream => Some(reader.u32()?),
+            _ => None,
+        };
+
+        let message = match tag {
+            b'B' => PgOutputMessage::Begin {
+                final_lsn: Lsn::new(reader.u64()?),
+                commit_time: reader.i64()?,
+                xid: reader.u32()?,
+            },
+            b'C' => PgOutputMessage::Commit {
+                flags: reader.u8()?,
+                commit_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                commit_time: reader.i64()?,
+            },
+            b'O' => PgOutputMessage::Origin {
+                commit_lsn: Lsn::new(reader.u64()?),
+                name: reader.cstring()?,
+            },
+            b'R' => {
+                let id = reader.u32()?;
+                let namespace = reader.cstring()?;
+                let name = reader.cstring()?;
+                let replica_identity = reader.u8()?;
+                let columns = (0..reader.i16()?)
+                    .map(|_| {
+                        Ok(PgOutputColumn {
+                            key: reader.u8()? & 1 == 1,
+                            name: reader.cstring()?,
+                            type_oid: reader.u32()?,
+                            type_modifier: reader.i32()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, ReplicationError>>()?;
+
+                PgOutputMessage::Relation(PgOutputRelation {
+                    id,
+                    namespace,
+                    name,
+                    replica_identity,
+                    columns,
+                })
+            }
+            b'Y' => PgOutputMessage::Type {
+                id: reader.u32()?,
+                namespace: reader.cstring()?,
+                name: reader.cstring()?,
+            },
+            b'I' => {
+                let relation_id = reader.u32()?;
+                reader.expect(b'N')?;
+                PgOutputMessage::Insert {
+                    relation_id,
+                    new: reader.tuple()?,
+                }
+            }
+            b'U' => {
+                let relation_id = reader.u32()?;
+                let old = match reader.u8()? {
+                    b'K' | b'O' => {
+                        let old = reader.tuple()?;
+                        reader.expect(b'N')?;
+                        Some(old)
+                    }
+                    b'N' => None,
+                    tag => return Err(unexpected(tag, "Update")),
+                };
+                PgOutputMessage::Update {
+                    relation_id,
+                    old,
+                    new: reader.tuple()?,
+                }
+            }
+            b'D' => {
+                let relation_id = reader.u32()?;
+                match reader.u8()? {
+                    b'K' | b'O' => PgOutputMessage::Delete {
+                        relation_id,
+                        old: reader.tuple()?,
+                    },
+                    tag => return Err(unexpected(tag, "Delete")),
+                }
+            }
+            b'T' => {
+                let relations = reader.u32()?;
+                let options = reader.u8()?;
+                PgOutputMessage::Truncate {
+                    options,
+                    relation_ids: (0..relations)
+                        .map(|_| reader.u32())
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            b'M' => {
+                let transactional = reader.u8()? & 1 == 1;
+                let lsn = Lsn::new(reader.u64()?);
+                let prefix = reader.cstring()?;
+                let len = reader.u32()? as usize;
+                PgOutputMessage::Message {
+                    transactional,
+                    lsn,
+                    prefix,
+                    content: reader.bytes(len)?,
+                }
+            }
+            b'S' => PgOutputMessage::StreamStart {
+                xid: reader.u32()?,
+                first_segment: reader.u8()? == 1,
+            },
+            b'E' => PgOutputMessage::StreamStop,
+            b'c' => PgOutputMessage::StreamCommit {
+                xid: reader.u32()?,
+                flags: reader.u8()?,
+                commit_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                commit_time: reader.i64()?,
+            },
+            b'A' => PgOutputMessage::StreamAbort {
+                xid: reader.u32()?,
+                subxid: reader.u32()?,
+            },
+            b'b' => PgOutputMessage::BeginPrepare {
+                prepare_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                prepare_time: reader.i64()?,
+                xid: reader.u32()?,
+                gid: reader.cstring()?,
+            },
+            b'P' => PgOutputMessage::Prepare {
+                flags: reader.u8()?,
+                prepare_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                prepare_time: reader.i64()?,
+                xid: reader.u32()?,
+                gid: reader.cstring()?,
+            },
+            b'K' => PgOutputMessage::CommitPrepared {
+                flags: reader.u8()?,
+                commit_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                commit_time: reader.i64()?,
+                xid: reader.u32()?,
+                gid: reader.cstring()?,
+            },
+            b'r' => PgOutputMessage::RollbackPrepared {
+                flags: reader.u8()?,
+                prepare_end_lsn: Lsn::new(reader.u64()?),
+                rollback_end_lsn: Lsn::new(reader.u64()?),
+                prepare_time: reader.i64()?,
+                rollback_time: reader.i64()?,
+                xid: reader.u32()?,
+                gid: reader.cstring()?,
+            },
+            b'p' => PgOutputMessage::StreamPrepare {
+                flags: reader.u8()?,
+                prepare_lsn: Lsn::new(reader.u64()?),
+                end_lsn: Lsn::new(reader.u64()?),
+                prepare_time: reader.i64()?,
+                xid: reader.u32()?,
+                gid: reader.cstring()?,
+            },
+            tag => return Err(unexpected(tag, "pgoutput")),
+        };
+
+        Ok((xid, message))
+    }
+}
+
+/// decodes `pgoutput` messages keeping track of the relations described by the server
+#[derive(Debug)]
+pub struct PgOutputDecoder {
+    publication_names: Vec<String>,
+    streaming: bool,
+    two_phase: bool,
+    relations: HashMap<u32, PgOutputRelation>,
+    /// whether a block of changes of a streamed transaction is being received
+    in_stream: bool,
+}
+
+impl PgOutputDecoder {
+    pub fn new(publication_names: Vec<String>) -> Self {
+        Self {
+            publication_names,
+            streaming: false,
+            two_phase: false,
+            relations: HashMap::new(),
+            in_stream: false,
+        }
+    }
+
+    /// requests large in-progress transactions to be streamed before they commit
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// requests prepared transactions to be decoded when prepared rather than once committed. the
+    /// slot must have been created with two-phase decoding, see `create_slot_options`.
+    pub fn two_phase(mut self, two_phase: bool) -> Self {
+        self.two_phase = two_phase;
+        self
+    }
+
+    fn relation(&self, relation_id: u32) -> Result<&PgOutputRelation, ReplicationError> {
+        self.relations.get(&relation_id).ok_or_else(|| {
+            ReplicationError::Plugin(format!("change for unknown relation {}", relation_id))
+        })
+    }
+}
+
+impl OutputPlugin for PgOutputDecoder {
+    fn name(&self) -> &str {
+        "pgoutput"
+    }
+
+    fn emits_truncate(&self) -> bool {
+        true
+    }
+
+    fn create_slot_options(&self) -> Option<String> {
+        match self.two_phase {
+            true => Some("TWO_PHASE".to_string()),
+            false => None,
+        }
+    }
+
+    fn start_replication_options(&self) -> Option<String> {
+        let publication_names = self
+            .publication_names
+            .iter()
+            .map(|name| format!("\"{}\"", name.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut options = format!(
+            "\"publication_names\" '{}'",
+            publication_names.replace('\'', "''")
+        );
+        if self.streaming {
+            options += ", \"streaming\" 'on'";
+        }
+        if self.two_phase {
+            options += ", \"two_phase\" 'on'";
+        }
+        // streaming requires protocol version 2 (Postgres 14) and two-phase commit 3 (Postgres 15)
+        let proto_version = match (self.streaming, self.two_phase) {
+            (_, true) => 3,
+            (true, false) => 2,
+            (false, false) => 1,
+        };
+        Some(format!(
+            "(\"proto_version\" '{}', {})",
+            proto_version, options
+        ))
+    }
+
+    fn decode(&mut self, lsn: Lsn, data: Bytes) -> Result<Option<PluginMessage>, ReplicationError> {
+        let (xid, message) = PgOutputMessage::parse_streamed(data, self.in_stream)?;
+
+        let change = match message {
+            PgOutputMessage::Begin {
+                commit_time, xid, ..
+            } => {
+                return Ok(Some(PluginMessage::Begin {
+                    xid: Some(xid),
+                    commit_time: Some(to_unix_micros(commit_time)),
+                }))
+            }
+            PgOutputMessage::Commit { commit_time, .. } => {
+                return Ok(Some(PluginMessage::Commit {
+                    commit_time: Some(to_unix_micros(commit_time)),
+                }))
+            }
+            PgOutputMessage::Relation(relation) => {
+                debug!("Got relation {}.{}", relation.namespace, relation.name);
+                self.relations.insert(relation.id, relation);
+                return Ok(None);
+            }
+            PgOutputMessage::Insert { relation_id, new } => {
+                let relation = self.relation(relation_id)?;
+                Change {
+                    table: table_name(relation),
+                    op: Op::Insert,
+                    old: vec![],
+                    new: columns(relation, new)?,
+                    lsn,
+                    truncate: None,
+                }
+            }
+            PgOutputMessage::Update {
+                relation_id,
+                old,
+                new,
+            } => {
+                let relation = self.relation(relation_id)?;
+                Change {
+                    table: table_name(relation),
+                    op: Op::Update,
+                    old: old
+                        .map(|old| columns(relation, old))
+                        .transpose()?
+                        .unwrap_or_default(),
+                    new: columns(relation, new)?,
+                    lsn,
+                    truncate: None,
+                }
+            }
+            PgOutputMessage::Delete { relation_id, old } => {
+                let relation = self.relation(relation_id)?;
+                Change {
+                    table: table_name(relation),
+                    op: Op::Delete,
+                    old: columns(relation, old)?,
+                    new: vec![],
+                    lsn,
+                    truncate: None,
+                }
+            }
+            PgOutputMessage::Truncate {
+                options,
+                relation_ids,
+            } => {
+                let tables = relation_ids
+                    .into_iter()
+                    .map(|relation_id| Ok(table_name(self.relation(relation_id)?)))
+                    .collect::<Result<Vec<_>, ReplicationError>>()?;
+                Change {
+                    table: tables.first().cloned().unwrap_or_default(),
+                    op: Op::Truncate,
+                    old: vec![],
+                    new: vec![],
+                    lsn,
+                    truncate: Some(Truncate {
+                        tables,
+                        cascade: options & TRUNCATE_CASCADE != 0,
+                        restart_identity: options & TRUNCATE_RESTART_IDENTITY != 0,
+                    }),
+                }
+            }
+            PgOutputMessage::StreamStart { xid, .. } => {
+                self.in_stream = true;
+                return Ok(Some(PluginMessage::StreamStart { xid }));
+            }
+            PgOutputMessage::StreamStop => {
+                self.in_stream = false;
+                return Ok(Some(PluginMessage::StreamStop));
+            }
+            PgOutputMessage::StreamCommit {
+                xid, commit_time, ..
+            } => {
+                return Ok(Some(PluginMessage::StreamCommit {
+                    xid,
+                    commit_time: Some(to_unix_micros(commit_time)),
+                }))
+            }
+            PgOutputMessage::StreamAbort { xid, subxid } => {
+                return Ok(Some(PluginMessage::StreamAbort { xid, subxid }))
+            }
+            PgOutputMessage::BeginPrepare {
+                prepare_time, xid, ..
+            } => {
+                return Ok(Some(PluginMessage::Begin {
+                    xid: Some(xid),
+                    commit_time: Some(to_unix_micros(prepare_time)),
+                }))
+            }
+            PgOutputMessage::Prepare {
+                prepare_time, gid, ..
+            } => {
+                return Ok(Some(PluginMessage::Prepare {
+                    gid,
+                    prepare_time: Some(to_unix_micros(prepare_time)),
+                }))
+            }
+            PgOutputMessage::CommitPrepared {
+                commit_time,
+                xid,
+                gid,
+                ..
+            } => {
+                return Ok(Some(PluginMessage::CommitPrepared {
+                    xid,
+                    gid,
+                    commit_time: Some(to_unix_micros(commit_time)),
+                }))
+            }
+            PgOutputMessage::RollbackPrepared {
+                rollback_time,
+                xid,
+                gid,
+                ..
+            } => {
+                return Ok(Some(PluginMessage::RollbackPrepared {
+                    xid,
+                    gid,
+                    rollback_time: Some(to_unix_micros(rollback_time)),
+                }))
+            }
+            PgOutputMessage::StreamPrepare {
+                prepare_time,
+                xid,
+                gid,
+                ..
+            } => {
+                return Ok(Some(PluginMessage::StreamPrepare {
+                    xid,
+                    gid,
+                    prepare_time: Some(to_unix_micros(prepare_time)),
+                }))
+            }
+            message @ (PgOutputMessage::Origin { .. }
+            | PgOutputMessage::Type { .. }
+            | PgOutputMessage::Message { .. }) => {
+                debug!("Skipping {:?}", message);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(match xid {
+            Some(xid) => PluginMessage::StreamChange { xid, change },
+            None => PluginMessage::Change(change),
+        }))
+    }
+}
+
+/// converts a server timestamp to microseconds since the unix epoch, clamping earlier times to it
+fn to_unix_micros(pg_timestamp: i64) -> u64 {
+    from_pg_timestamp(pg_timestamp)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_micros() as u64)
+}
+
+fn table_name(relation: &PgOutputRelation) -> String {
+    // pg_catalog is sent as an empty namespace
+    match relation.namespace.as_str() {
+        "" => format!("pg_catalog.{}", relation.name),
+        namespace => format!("{}.{}", namespace, relation.name),
+    }
+}
+
+fn columns(
+    relation: &PgOutputRelation,
+    tuple: PgOutputTuple,
+) -> Result<Vec<Column>, ReplicationError> {
+    if tuple.len() != relation.columns.len() {
+        return Err(ReplicationError::Plugin(format!(
+            "tuple with {} columns for relation {} with {} columns",
+            tuple.len(),
+            table_name(relation),
+            relation.columns.len()
+        )));
+    }
+
+    relation
+        .columns
+        .iter()
+        .zip(tuple)
+        .map(|(column, value)| {
+            let value = match value {
+                PgOutputValue::Null => Value::Null,
+                PgOutputValue::UnchangedToast => Value::UnchangedToast,
+                PgOutputValue::Text(text) => {
+                    let text = std::str::from_utf8(&text).map_err(|err| {
+                        ReplicationError::Plugin(format!(
+                            "column {} is not valid utf8: {}",
+                            column.name, err
+                        ))
+                    })?;
+                    Value::from_text(Some(column.type_oid), text)?
+                }
+                // only sent when the binary option is requested which it never is
+                PgOutputValue::Binary(_) => {
+                    return Err(ReplicationError::Plugin(format!(
+                        "unsupported binary value for column {}",
+                        column.name
+                    )))
+                }
+            };
+
+            Ok(Column {
+                name: column.name.clone(),
+                type_oid: Some(column.type_oid),
+                value,
+            })
+        })
+        .collect()
+}
+
+fn unexpected(tag: u8, message: &str) -> ReplicationError {
+    ReplicationError::Plugin(format!(
+        "unexpected byte {:?} in {} message",
+        tag as char, message
+    ))
+}
+
+/// reads big-endian values from a message failing instead of panicking on truncated input
+struct Reader(Bytes);
+
+impl Reader {
+    fn ensure(&self, len: usize) -> Result<(), ReplicationError> {
+        if self.0.remaining() < len {
+            return Err(ReplicationError::Plugin(format!(
+                "pgoutput message truncated: expected {} more bytes but got {}",
+                len,
+                self.0.remaining()
+            )));
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, ReplicationError> {
+        self.ensure(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn i16(&mut self) -> Result<i16, ReplicationError> {
+        self.ensure(2)?;
+        Ok(self.0.get_i16())
+    }
+
+    fn u32(&mut self) -> Result<u32, ReplicationError> {
+        self.ensure(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    fn i32(&mut self) -> Result<i32, ReplicationError> {
+        self.ensure(4)?;
+        Ok(self.0.get_i32())
+    }
+
+    fn u64(&mut self) -> Result<u64, ReplicationError> {
+        self.ensure(8)?;
+        Ok(self.0.get_u64())
+    }
+
+    fn i64(&mut self) -> Result<i64, ReplicationError> {
+        self.ensure(8)?;
+        Ok(self.0.get_i64())
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Bytes, ReplicationError> {
+        self.ensure(len)?;
+        Ok(self.0.split_to(len))
+    }
+
+    fn cstring(&mut self) -> Result<String, ReplicationError> {
+        let len = self.0.iter().position(|b| *b == 0).ok_or_else(|| {
+            ReplicationError::Plugin("unterminated string in pgoutput message".to_string())
+        })?;
+        let string = self.0.split_to(len);
+        self.0.advance(1);
+        String::from_utf8(string.to_vec())
+            .map_err(|err| ReplicationError::Plugin(format!("invalid utf8 string: {}", err)))
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), ReplicationError> {
+        match self.u8()? {
+            tag if tag == expected => Ok(()),
+            tag => Err(unexpected(tag, "tuple")),
+        }
+    }
+
+    fn tuple(&mut self) -> Result<PgOutputTuple, ReplicationError> {
+        (0..self.i16()?)
+            .map(|_| match self.u8()? {
+                b'n' => Ok(PgOutputValue::Null),
+                b'u' => Ok(PgOutputValue::UnchangedToast),
+                b't' => {
+                    let len = self.u32()? as usize;
+                    Ok(PgOutputValue::Text(self.bytes(len)?))
+                }
+                b'b' => {
+                    let len = self.u32()? as usize;
+                    Ok(PgOutputValue::Binary(self.bytes(len)?))
+                }
+                tag => Err(unexpected(tag, "tuple")),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+
+    fn cstring(buf: &mut BytesMut, value: &str) {
+        buf.put_slice(value.as_bytes());
+        buf.put_u8(0);
+    }
+
+    fn relation() -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'R');
+        buf.put_slice(&relation_body());
+        buf.freeze()
+    }
+
+    fn relation_body() -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u32(16384);
+        cstring(&mut buf, "public");
+        cstring(&mut buf, "tenants");
+        buf.put_u8(b'd');
+        buf.put_i16(2);
+        buf.put_u8(1);
+        cstring(&mut buf, "id");
+        buf.put_u32(2950);
+        buf.put_i32(-1);
+        buf.put_u8(0);
+        cstring(&mut buf, "name");
+        buf.put_u32(25);
+        buf.put_i32(-1);
+        buf.freeze()
+    }
+
+    fn tuple(buf: &mut BytesMut, values: &[Option<&str>]) {
+        buf.put_i16(values.len() as i16);
+        for value in values {
+            match value {
+                Some(value) => {
+                    buf.put_u8(b't');
+                    buf.put_u32(value.len() as u32);
+                    buf.put_slice(value.as_bytes());
+                }
+                None => buf.put_u8(b'n'),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_transaction() -> Result<(), ReplicationError> {
+        let mut decoder = PgOutputDecoder::new(vec!["tenants".to_string()]);
+        let id = "c497c1be-cf70-41aa-8665-971e2ffaefcd";
+        let lsn = Lsn::new(0x0100);
+
+        let mut begin = BytesMut::new();
+        begin.put_u8(b'B');
+        begin.put_u64(0x0100);
+        begin.put_i64(1_000_000);
+        begin.put_u32(733);
+        assert_eq!(
+            decoder.decode(lsn, begin.freeze())?,
+            Some(PluginMessage::Begin {
+                xid: Some(733),
+                commit_time: Some(946_684_801_000_000),
+            })
+        );
+
+        assert_eq!(decoder.decode(lsn, relation())?, None);
+
+        let mut insert = BytesMut::new();
+        insert.put_u8(b'I');
+        insert.put_u32(16384);
+        insert.put_u8(b'N');
+        tuple(&mut insert, &[Some(id), None]);
+        assert_eq!(
+            decoder.decode(lsn, insert.freeze())?,
+            Some(PluginMessage::Change(Change {
+                table: "public.tenants".to_string(),
+                op: Op::Insert,
+                old: vec![],
+                new: vec![
+                    Column {
+                        name: "id".to_string(),
+                        type_oid: Some(2950),
+                        value: Value::Uuid(id.parse().unwrap()),
+                    },
+                    Column {
+                        name: "name".to_string(),
+                        type_oid: Some(25),
+                        value: Value::Null,
+                    },
+                ],
+                lsn,
+                truncate: None,
+            }))
+        );
+
+        let mut update = BytesMut::new();
+        update.put_u8(b'U');
+        update.put_u32(16384);
+        update.put_u8(b'N');
+        tuple(&mut update, &[Some(id), Some("tenant1")]);
+        let update = match decoder.decode(lsn, update.freeze())? {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        };
+        assert_eq!(update.op, Op::Update);
+        assert!(update.old.is_empty());
+        assert_eq!(
+            update.new_value("name"),
+            Some(&Value::Text("tenant1".to_string()))
+        );
+
+        let mut delete = BytesMut::new();
+        delete.put_u8(b'D');
+        delete.put_u32(16384);
+        delete.put_u8(b'K');
+        tuple(&mut delete, &[Some(id), None]);
+        let delete = match decoder.decode(lsn, delete.freeze())? {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        };
+        assert_eq!(delete.op, Op::Delete);
+        assert_eq!(
+            delete.old_value("id"),
+            Some(&Value::Uuid(id.parse().unwrap()))
+        );
+
+        let mut truncate = BytesMut::new();
+        truncate.put_u8(b'T');
+        truncate.put_u32(1);
+        truncate.put_u8(TRUNCATE_CASCADE | TRUNCATE_RESTART_IDENTITY);
+        truncate.put_u32(16384);
+        assert_eq!(
+            decoder.decode(lsn, truncate.freeze())?,
+            Some(PluginMessage::Change(Change {
+                table: "public.tenants".to_string(),
+                op: Op::Truncate,
+                old: vec![],
+                new: vec![],
+                lsn,
+                truncate: Some(Truncate {
+                    tables: vec!["public.tenants".to_string()],
+                    cascade: true,
+                    restart_identity: true,
+                }),
+            }))
+        );
+
+        let mut commit = BytesMut::new();
+        commit.put_u8(b'C');
+        commit.put_u8(0);
+        commit.put_u64(0x0100);
+        commit.put_u64(0x0180);
+        commit.put_i64(1_000_000);
+        assert_eq!(
+            decoder.decode(lsn, commit.freeze())?,
+            Some(PluginMessage::Commit {
+                commit_time: Some(946_684_801_000_000),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_stream() -> Result<(), ReplicationError> {
+        let mut decoder = PgOutputDecoder::new(vec!["tenants".to_string()]).streaming(true);
+        let lsn = Lsn::new(0x0100);
+
+        let mut start = BytesMut::new();
+        start.put_u8(b'S');
+        start.put_u32(733);
+        start.put_u8(1);
+        assert_eq!(
+            decoder.decode(lsn, start.freeze())?,
+            Some(PluginMessage::StreamStart { xid: 733 })
+        );
+
+        // within a block messages are prefixed with the xid of their (sub)transaction
+        let mut relation = BytesMut::new();
+        relation.put_u8(b'R');
+        relation.put_u32(733);
+        relation.put_slice(&relation_body());
+        assert_eq!(decoder.decode(lsn, relation.freeze())?, None);
+
+        let mut insert = BytesMut::new();
+        insert.put_u8(b'I');
+        insert.put_u32(734);
+        insert.put_u32(16384);
+        insert.put_u8(b'N');
+        tuple(
+            &mut insert,
+            &[Some("c497c1be-cf70-41aa-8665-971e2ffaefcd"), None],
+        );
+        let insert = insert.freeze();
+        match decoder.decode(lsn, insert.clone())? {
+            Some(PluginMessage::StreamChange { xid: 734, change }) => {
+                assert_eq!(change.table, "public.tenants");
+                assert_eq!(change.op, Op::Insert);
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+
+        assert_eq!(
+            decoder.decode(lsn, Bytes::from_static(b"E"))?,
+            Some(PluginMessage::StreamStop)
+        );
+        // outside of a block the xid is not expected
+        assert!(decoder.decode(lsn, insert).is_err());
+
+        let mut abort = BytesMut::new();
+        abort.put_u8(b'A');
+        abort.put_u32(733);
+        abort.put_u32(734);
+        assert_eq!(
+            decoder.decode(lsn, abort.freeze())?,
+            Some(PluginMessage::StreamAbort {
+                xid: 733,
+                subxid: 734
+            })
+        );
+
+        let mut commit = BytesMut::new();
+        commit.put_u8(b'c');
+        commit.put_u32(733);
+        commit.put_u8(0);
+        commit.put_u64(0x0100);
+        commit.put_u64(0x0180);
+        commit.put_i64(1_000_000);
+        assert_eq!(
+            decoder.decode(lsn, commit.freeze())?,
+            Some(PluginMessage::StreamCommit {
+                xid: 733,
+                commit_time: Some(946_684_801_000_000),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_two_phase() -> Result<(), ReplicationError> {
+        let mut decoder = PgOutputDecoder::new(vec!["tenants".to_string()]).two_phase(true);
+        let lsn = Lsn::new(0x0100);
+
+        let mut begin = BytesMut::new();
+        begin.put_u8(b'b');
+        begin.put_u64(0x0100);
+        begin.put_u64(0x0180);
+        begin.put_i64(1_000_000);
+        begin.put_u32(733);
+        cstring(&mut begin, "workflow-1");
+        assert_eq!(
+            decoder.decode(lsn, begin.freeze())?,
+            Some(PluginMessage::Begin {
+                xid: Some(733),
+                commit_time: Some(946_684_801_000_000),
+            })
+        );
+
+        let mut prepare = BytesMut::new();
+        prepare.put_u8(b'P');
+        prepare.put_u8(0);
+        prepare.put_u64(0x0100);
+        prepare.put_u64(0x0180);
+        prepare.put_i64(1_000_000);
+        prepare.put_u32(733);
+        cstring(&mut prepare, "workflow-1");
+        assert_eq!(
+            decoder.decode(lsn, prepare.freeze())?,
+            Some(PluginMessage::Prepare {
+                gid: "workflow-1".to_string(),
+                prepare_time: Some(946_684_801_000_000),
+            })
+        );
+
+        let mut commit = BytesMut::new();
+        commit.put_u8(b'K');
+        commit.put_u8(0);
+        commit.put_u64(0x0200);
+        commit.put_u64(0x0280);
+        commit.put_i64(2_000_000);
+        commit.put_u32(733);
+        cstring(&mut commit, "workflow-1");
+        assert_eq!(
+            decoder.decode(lsn, commit.freeze())?,
+            Some(PluginMessage::CommitPrepared {
+                xid: 733,
+                gid: "workflow-1".to_string(),
+                commit_time: Some(946_684_802_000_000),
+            })
+        );
+
+        let mut rollback = BytesMut::new();
+        rollback.put_u8(b'r');
+        rollback.put_u8(0);
+        rollback.put_u64(0x0180);
+        rollback.put_u64(0x0300);
+        rollback.put_i64(1_000_000);
+        rollback.put_i64(3_000_000);
+        rollback.put_u32(734);
+        cstring(&mut rollback, "workflow-2");
+        assert_eq!(
+            decoder.decode(lsn, rollback.freeze())?,
+            Some(PluginMessage::RollbackPrepared {
+                xid: 734,
+                gid: "workflow-2".to_string(),
+                rollback_time: Some(946_684_803_000_000),
+            })
+        );
+
+        let mut stream_prepare = BytesMut::new();
+        stream_prepare.put_u8(b'p');
+        stream_prepare.put_u8(0);
+        stream_prepare.put_u64(0x0400);
+        stream_prepare.put_u64(0x0480);
+        stream_prepare.put_i64(4_000_000);
+        stream_prepare.put_u32(735);
+        cstring(&mut stream_prepare, "workflow-3");
+        assert_eq!(
+            decoder.decode(lsn, stream_prepare.freeze())?,
+            Some(PluginMessage::StreamPrepare {
+                xid: 735,
+                gid: "workflow-3".to_string(),
+                prepare_time: Some(946_684_804_000_000),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = PgOutputDecoder::new(vec![]);
+
+        let mut insert = BytesMut::new();
+        insert.put_u8(b'I');
+        insert.put_u32(16384);
+        insert.put_u8(b'N');
+        tuple(&mut insert, &[Some("a"), None]);
+        let insert = insert.freeze();
+
+        // change before its relation was described
+        assert!(matches!(
+            decoder.decode(Lsn::INVALID, insert.clone()),
+            Err(ReplicationError::Plugin(_))
+        ));
+
+        // truncated message
+        decoder.decode(Lsn::INVALID, relation()).unwrap();
+        assert!(matches!(
+            decoder.decode(Lsn::INVALID, insert.slice(..insert.len() - 1)),
+            Err(ReplicationError::Plugin(_))
+        ));
+        assert!(matches!(
+            decoder.decode(Lsn::INVALID, Bytes::from_static(b"X")),
+            Err(ReplicationError::Plugin(_))
+        ));
+    }
+
+    #[test]
+    fn test_table_name() {
+        let relation = |namespace: &str| PgOutputRelation {
+            id: 16384,
+            namespace: namespace.to_string(),
+            name: "Tenant Events".to_string(),
+            replica_identity: b'd',
+            columns: vec![],
+        };
+        assert_eq!(
+            table_name(&relation("My Schema")),
+            "My Schema.Tenant Events"
+        );
+        assert_eq!(table_name(&relation("")), "pg_catalog.Tenant Events");
+    }
+}
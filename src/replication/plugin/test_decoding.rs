@@ -0,0 +1,442 @@
+use super::{parse_timestamp, OutputPlugin, PluginMessage};
+use crate::replication::{value::oid, Change, Column, Lsn, Op, ReplicationError, Truncate, Value};
+use bytes::Bytes;
+use tracing::debug;
+
+/// decodes the textual output of the `test_decoding` plugin
+///
+/// the format is meant for debugging rather than machine consumption so only the shapes printed by
+/// the plugin with `include-xids` and `include-timestamp` enabled are understood, e.g.
+///
+/// ```text
+/// BEGIN 733
+/// table public.tenants: INSERT: id[uuid]:'c497c1be-cf70-41aa-8665-971e2ffaefcd' count[integer]:3
+/// COMMIT 733 (at 2022-11-10 12:00:00.123456+00)
+/// ```
+#[derive(Debug, Default)]
+pub struct TestDecodingDecoder;
+
+impl OutputPlugin for TestDecodingDecoder {
+    fn name(&self) -> &str {
+        "test_decoding"
+    }
+
+    fn emits_truncate(&self) -> bool {
+        true
+    }
+
+    fn start_replication_options(&self) -> Option<String> {
+        Some(
+            "(\"include-xids\" '1', \"include-timestamp\" '1', \"skip-empty-xacts\" '1')"
+                .to_string(),
+        )
+    }
+
+    fn decode(&mut self, lsn: Lsn, data: Bytes) -> Result<Option<PluginMessage>, ReplicationError> {
+        let line = std::str::from_utf8(&data).map_err(|err| {
+            ReplicationError::Plugin(format!("invalid test_decoding message: {}", err))
+        })?;
+
+        if let Some(rest) = line.strip_prefix("BEGIN") {
+            return Ok(Some(PluginMessage::Begin {
+                xid: parse_xid(rest)?,
+                commit_time: None,
+            }));
+        }
+
+        if let Some(rest) = line.strip_prefix("COMMIT") {
+            let commit_time = match rest.split_once(" (at ") {
+                Some((_, timestamp)) => {
+                    let timestamp = timestamp.strip_suffix(')').ok_or_else(|| {
+                        ReplicationError::Plugin(format!("invalid COMMIT: {:?}", line))
+                    })?;
+                    Some(parse_timestamp(timestamp)?)
+                }
+                None => None,
+            };
+            return Ok(Some(PluginMessage::Commit { commit_time }));
+        }
+
+        if line.starts_with("message:") {
+            debug!("Skipping {:?}", line);
+            return Ok(None);
+        }
+
+        let mut parser = Parser::new(line);
+        parser.expect("table ")?;
+        // a TRUNCATE lists every table truncated together
+        let mut tables = vec![parser.qualified_name()?];
+        while parser.consume(", ") {
+            tables.push(parser.qualified_name()?);
+        }
+        parser.expect(": ")?;
+        let op = parser.until(':')?;
+        parser.expect(":")?;
+        parser.skip_space();
+
+        let mut truncate = None;
+        let (op, old, new) = match op {
+            "INSERT" => (Op::Insert, vec![], parser.columns()?),
+            "UPDATE" => {
+                let old = if parser.consume("old-key: ") {
+                    parser.columns()?
+                } else {
+                    vec![]
+                };
+                parser.consume("new-tuple: ");
+                (Op::Update, old, parser.columns()?)
+            }
+            "DELETE" => (Op::Delete, parser.columns()?, vec![]),
+            "TRUNCATE" => {
+                let options = parser.rest.split(' ').collect::<Vec<_>>();
+                truncate = Some(Truncate {
+                    tables: tables.clone(),
+                    cascade: options.contains(&"cascade"),
+                    restart_identity: options.contains(&"restart_seqs"),
+                });
+                (Op::Truncate, vec![], vec![])
+            }
+            op => {
+                return Err(ReplicationError::Plugin(format!(
+                    "unknown test_decoding operation: {:?}",
+                    op
+                )))
+            }
+        };
+
+        Ok(Some(PluginMessage::Change(Change {
+            table: tables.swap_remove(0),
+            op,
+            old,
+            new,
+            lsn,
+            truncate,
+        })))
+    }
+}
+
+fn parse_xid(xid: &str) -> Result<Option<u32>, ReplicationError> {
+    match xid.trim() {
+        "" => Ok(None),
+        xid => xid
+            .parse()
+            .map(Some)
+            .map_err(|_| ReplicationError::Plugin(format!("invalid xid: {:?}", xid))),
+    }
+}
+
+/// the oid of the builtin types by the name `format_type` prints them with
+fn type_oid(type_name: &str) -> Option<u32> {
+    // drop type modifiers such as `character varying(255)` or `timestamp(3) with time zone`
+    let type_name = match type_name.split_once('(') {
+        Some((name, rest)) => match rest.split_once(')') {
+            Some((_, suffix)) => format!("{}{}", name, suffix),
+            None => type_name.to_string(),
+        },
+        None => type_name.to_string(),
+    };
+    let (element_name, is_array) = match type_name.strip_suffix("[]") {
+        Some(element_name) => (element_name, true),
+        None => (type_name.as_str(), false),
+    };
+
+    let (element, array) = match element_name {
+        "boolean" => (oid::BOOL, oid::BOOL_ARRAY),
+        "bytea" => (oid::BYTEA, oid::BYTEA_ARRAY),
+        "bigint" => (oid::INT8, oid::INT8_ARRAY),
+        "smallint" => (oid::INT2, oid::INT2_ARRAY),
+        "integer" => (oid::INT4, oid::INT4_ARRAY),
+        "text" => (oid::TEXT, oid::TEXT_ARRAY),
+        "json" => (oid::JSON, oid::JSON_ARRAY),
+        "point" => (oid::POINT, oid::POINT_ARRAY),
+        "real" => (oid::FLOAT4, oid::FLOAT4_ARRAY),
+        "double precision" => (oid::FLOAT8, oid::FLOAT8_ARRAY),
+        "character" => (oid::BPCHAR, oid::BPCHAR_ARRAY),
+        "character varying" => (oid::VARCHAR, oid::VARCHAR_ARRAY),
+        "date" => (oid::DATE, oid::DATE_ARRAY),
+        "timestamp without time zone" => (oid::TIMESTAMP, oid::TIMESTAMP_ARRAY),
+        "timestamp with time zone" => (oid::TIMESTAMPTZ, oid::TIMESTAMPTZ_ARRAY),
+        "numeric" => (oid::NUMERIC, oid::NUMERIC_ARRAY),
+        "uuid" => (oid::UUID, oid::UUID_ARRAY),
+        "jsonb" => (oid::JSONB, oid::JSONB_ARRAY),
+        _ => return None,
+    };
+    Some(if is_array { array } else { element })
+}
+
+/// a cursor over a single line of `test_decoding` output
+struct Parser<'a> {
+    line: &'a str,
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(line: &'a str) -> Self {
+        Self { line, rest: line }
+    }
+
+    fn error(&self, message: &str) -> ReplicationError {
+        ReplicationError::Plugin(format!(
+            "{} at offset {} of {:?}",
+            message,
+            self.line.len() - self.rest.len(),
+            self.line
+        ))
+    }
+
+    fn consume(&mut self, prefix: &str) -> bool {
+        match self.rest.strip_prefix(prefix) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), ReplicationError> {
+        if self.consume(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", prefix)))
+        }
+    }
+
+    fn skip_space(&mut self) {
+        self.rest = self.rest.trim_start_matches(' ');
+    }
+
+    /// everything up to but excluding the delimiter
+    fn until(&mut self, delimiter: char) -> Result<&'a str, ReplicationError> {
+        let end = self
+            .rest
+            .find(delimiter)
+            .ok_or_else(|| self.error(&format!("expected {:?}", delimiter)))?;
+        let (value, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(value)
+    }
+
+    /// an identifier which is double quoted if it isn't a plain lowercase name
+    fn identifier(&mut self, delimiters: &[char]) -> Result<String, ReplicationError> {
+        if self.consume("\"") {
+            self.quoted('"')
+        } else {
+            let end = self
+                .rest
+                .find(delimiters)
+                .ok_or_else(|| self.error("unterminated identifier"))?;
+            let (identifier, rest) = self.rest.split_at(end);
+            self.rest = rest;
+            Ok(identifier.to_string())
+        }
+    }
+
+    fn qualified_name(&mut self) -> Result<String, ReplicationError> {
+        let schema = self.identifier(&['.'])?;
+        self.expect(".")?;
+        let table = self.identifier(&[':', ','])?;
+        Ok(format!("{}.{}", schema, table))
+    }
+
+    /// the rest of a string whose opening quote was consumed. quotes inside are doubled.
+    fn quoted(&mut self, quote: char) -> Result<String, ReplicationError> {
+        let mut value = String::new();
+        let mut chars = self.rest.char_indices().peekable();
+        while let Some((index, char)) = chars.next() {
+            if char != quote {
+                value.push(char);
+            } else if matches!(chars.peek(), Some((_, next)) if *next == quote) {
+                value.push(quote);
+                chars.next();
+            } else {
+                self.rest = &self.rest[index + 1..];
+                return Ok(value);
+            }
+        }
+        Err(self.error("unterminated quoted string"))
+    }
+
+    fn columns(&mut self) -> Result<Vec<Column>, ReplicationError> {
+        if self.consume("(no-tuple-data)") {
+            return Ok(vec![]);
+        }
+
+        let mut columns = vec![];
+        while !self.rest.is_empty() && !self.rest.starts_with("new-tuple: ") {
+            columns.push(self.column()?);
+            self.skip_space();
+        }
+        Ok(columns)
+    }
+
+    fn column(&mut self) -> Result<Column, ReplicationError> {
+        let name = self.identifier(&['['])?;
+        self.expect("[")?;
+        // array types end in brackets themselves, e.g. `tags[text[]]:'{a,b}'`
+        let end = self
+            .rest
+            .find("]:")
+            .ok_or_else(|| self.error("unterminated column type"))?;
+        let type_name = &self.rest[..end];
+        self.rest = &self.rest[end + 2..];
+        let type_oid = type_oid(type_name);
+
+        let value = if self.consume("'") {
+            Value::from_text(type_oid, &self.quoted('\'')?)
+        } else {
+            let end = self.rest.find(' ').unwrap_or(self.rest.len());
+            let value = &self.rest[..end];
+            self.rest = &self.rest[end..];
+
+            match value {
+                "null" => Ok(Value::Null),
+                "unchanged-toast-datum" => Ok(Value::UnchangedToast),
+                // numbers and booleans are printed without quotes
+                value => Value::from_text(type_oid, value),
+            }
+        }
+        .map_err(|_| self.error(&format!("invalid {} value", type_name)))?;
+
+        Ok(Column {
+            name,
+            type_oid,
+            value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(line: &'static str) -> Option<PluginMessage> {
+        TestDecodingDecoder
+            .decode(Lsn::new(0x0100), Bytes::from_static(line.as_bytes()))
+            .unwrap()
+    }
+
+    fn change(line: &'static str) -> Change {
+        match decode(line) {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    fn columns(columns: &[Column]) -> Vec<(&str, Option<u32>, Value)> {
+        columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.type_oid, column.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_transaction() {
+        assert_eq!(
+            decode("BEGIN 733"),
+            Some(PluginMessage::Begin {
+                xid: Some(733),
+                commit_time: None,
+            })
+        );
+
+        let insert = change(
+            "table public.tenants: INSERT: id[uuid]:'c497c1be-cf70-41aa-8665-971e2ffaefcd' \
+             name[character varying(255)]:'it''s' count[integer]:3 amount[numeric]:1.50 \
+             note[text]:null tags[text[]]:'{a,b}'",
+        );
+        assert_eq!(insert.op, Op::Insert);
+        assert_eq!(insert.table, "public.tenants");
+        assert_eq!(insert.lsn, Lsn::new(0x0100));
+        assert_eq!(
+            columns(&insert.new),
+            vec![
+                (
+                    "id",
+                    Some(2950),
+                    Value::Uuid("c497c1be-cf70-41aa-8665-971e2ffaefcd".parse().unwrap())
+                ),
+                ("name", Some(1043), Value::Text("it's".to_string())),
+                ("count", Some(23), Value::Int4(3)),
+                ("amount", Some(1700), Value::Numeric("1.50".to_string())),
+                ("note", Some(25), Value::Null),
+                (
+                    "tags",
+                    Some(1009),
+                    Value::Array(vec![
+                        Value::Text("a".to_string()),
+                        Value::Text("b".to_string())
+                    ])
+                ),
+            ]
+        );
+
+        let update = change(
+            "table \"My Schema\".\"a:b\": UPDATE: old-key: id[bigint]:1 new-tuple: id[bigint]:2 \
+             \"Body\"[text]:unchanged-toast-datum path[text]:'C:\\tmp'",
+        );
+        assert_eq!(update.op, Op::Update);
+        assert_eq!(update.table, "My Schema.a:b");
+        assert_eq!(columns(&update.old), vec![("id", Some(20), Value::Int8(1))]);
+        assert_eq!(
+            columns(&update.new),
+            vec![
+                ("id", Some(20), Value::Int8(2)),
+                ("Body", Some(25), Value::UnchangedToast),
+                ("path", Some(25), Value::Text("C:\\tmp".to_string())),
+            ]
+        );
+
+        let delete = change("table public.tenants: DELETE: (no-tuple-data)");
+        assert_eq!(delete.op, Op::Delete);
+        assert!(delete.old.is_empty());
+
+        let truncate =
+            change("table public.tenants, \"My Schema\".audit: TRUNCATE: restart_seqs cascade");
+        assert_eq!(truncate.op, Op::Truncate);
+        assert_eq!(truncate.table, "public.tenants");
+        assert_eq!(
+            truncate.truncate,
+            Some(Truncate {
+                tables: vec!["public.tenants".to_string(), "My Schema.audit".to_string()],
+                cascade: true,
+                restart_identity: true,
+            })
+        );
+        let truncate = change("table public.tenants: TRUNCATE: (no-flags)");
+        assert_eq!(
+            truncate.truncate,
+            Some(Truncate {
+                tables: vec!["public.tenants".to_string()],
+                ..Default::default()
+            })
+        );
+
+        assert_eq!(
+            decode("COMMIT 733 (at 2000-01-01 02:00:01.5+02)"),
+            Some(PluginMessage::Commit {
+                commit_time: Some(946_684_801_500_000),
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        for line in [
+            "BEGIN abc",
+            "table public.tenants: UPSERT: id[integer]:1",
+            "table public.tenants INSERT: id[integer]:1",
+            "table public.tenants: INSERT: id[integer]:'1",
+            "table public.tenants: INSERT: id[integer]:one",
+            "COMMIT 733 (at yesterday)",
+        ] {
+            assert!(
+                TestDecodingDecoder
+                    .decode(Lsn::INVALID, Bytes::from_static(line.as_bytes()))
+                    .is_err(),
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use super::{parse_timestamp, OutputPlugin, PluginMessage};
+use crate::replication::{value::oid, Change, Column, Lsn, Op, ReplicationError, Truncate, Value};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tracing::debug;
+
+/// a column in the `columns` or `identity` array of a wal2json message
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Wal2JsonColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    #[serde(rename = "typeoid")]
+    pub type_oid: Option<u32>,
+    pub value: JsonValue,
+}
+
+/// a single wal2json (format-version 2) message describing one action
+///
+/// see here for format details: https://github.com/eulerto/wal2json#format-version-2
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Wal2JsonMessage {
+    pub action: String,
+    pub xid: Option<u32>,
+    pub timestamp: Option<String>,
+    pub schema: Option<String>,
+    pub table: Option<String>,
+    #[serde(default)]
+    pub columns: Vec<Wal2JsonColumn>,
+    #[serde(default)]
+    pub identity: Vec<Wal2JsonColumn>,
+}
+
+/// decodes wal2json (format version 2) messages
+#[derive(Debug, Default)]
+pub struct Wal2JsonDecoder;
+
+impl OutputPlugin for Wal2JsonDecoder {
+    fn name(&self) -> &str {
+        "wal2json"
+    }
+
+    fn emits_truncate(&self) -> bool {
+        true
+    }
+
+    fn start_replication_options(&self) -> Option<String> {
+        // numbers are sent as strings since they would otherwise be parsed into an `f64` and lose
+        // digits (wal2json 2.4+)
+        Some(
+            "(\"format-version\" '2', \"include-xids\" '1', \"include-timestamp\" '1', \"include-type-oids\" '1', \"numeric-data-types-as-string\" '1')"
+                .to_string(),
+        )
+    }
+
+    fn decode(&mut self, lsn: Lsn, data: Bytes) -> Result<Option<PluginMessage>, ReplicationError> {
+        let message = serde_json::from_slice::<Wal2JsonMessage>(&data).map_err(|err| {
+            ReplicationError::Plugin(format!("invalid wal2json message: {}", err))
+        })?;
+
+        let commit_time = message
+            .timestamp
+            .as_deref()
+            .map(parse_timestamp)
+            .transpose()?;
+
+        let op = match message.action.as_str() {
+            "B" => {
+                return Ok(Some(PluginMessage::Begin {
+                    xid: message.xid,
+                    commit_time,
+                }))
+            }
+            "C" => return Ok(Some(PluginMessage::Commit { commit_time })),
+            "I" => Op::Insert,
+            "U" => Op::Update,
+            "D" => Op::Delete,
+            "T" => Op::Truncate,
+            "M" => {
+                debug!("Skipping {:?}", message);
+                return Ok(None);
+            }
+            action => {
+                return Err(ReplicationError::Plugin(format!(
+                    "unknown wal2json action: {:?}",
+                    action
+                )))
+            }
+        };
+
+        let table = match (&message.schema, &message.table) {
+            (Some(schema), Some(table)) => format!("{}.{}", schema, table),
+            _ => {
+                return Err(ReplicationError::Plugin(format!(
+                    "change without schema or table: {:?}",
+                    message
+                )))
+            }
+        };
+
+        // each truncated table is sent on its own without the options of the statement
+        let truncate = (op == Op::Truncate).then(|| Truncate {
+            tables: vec![table.clone()],
+            ..Default::default()
+        });
+        Ok(Some(PluginMessage::Change(Change {
+            table,
+            op,
+            old: columns(message.identity)?,
+            new: columns(message.columns)?,
+            lsn,
+            truncate,
+        })))
+    }
+}
+
+fn columns(columns: Vec<Wal2JsonColumn>) -> Result<Vec<Column>, ReplicationError> {
+    columns
+        .into_iter()
+        .map(|column| {
+            let value = match column.value {
+                JsonValue::Null => Value::Null,
+                // json and jsonb columns are embedded in the document as is, including scalars
+                value if matches!(column.type_oid, Some(oid::JSON) | Some(oid::JSONB)) => {
+                    Value::Json(value)
+                }
+                JsonValue::Bool(value) => Value::Bool(value),
+                // only exact for integers and floats, numerics are sent as strings instead
+                JsonValue::Number(number) => {
+                    Value::from_text(column.type_oid, &number.to_string())?
+                }
+                JsonValue::String(value) => Value::from_text(column.type_oid, &value)?,
+                value => {
+                    return Err(ReplicationError::Plugin(format!(
+                        "unexpected value for column {}: {}",
+                        column.name, value
+                    )))
+                }
+            };
+
+            Ok(Column {
+                name: column.name,
+                type_oid: column.type_oid,
+                value,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(message: &str) -> Option<PluginMessage> {
+        Wal2JsonDecoder
+            .decode(Lsn::new(0x0100), Bytes::from(message.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode_transaction() {
+        assert_eq!(
+            decode(r#"{"action":"B","xid":733,"timestamp":"2000-01-01 00:00:01.5+00"}"#),
+            Some(PluginMessage::Begin {
+                xid: Some(733),
+                commit_time: Some(946_684_801_500_000),
+            })
+        );
+
+        let insert = match decode(
+            r#"{"action":"I","schema":"public","table":"tenants","columns":[
+                {"name":"id","type":"uuid","typeoid":2950,"value":"c497c1be-cf70-41aa-8665-971e2ffaefcd"},
+                {"name":"count","type":"integer","typeoid":23,"value":"3"},
+                {"name":"amount","type":"numeric","typeoid":1700,"value":"1.50"},
+                {"name":"total","type":"numeric","typeoid":1700,"value":"12345678901234567890.123456789"},
+                {"name":"big","type":"bigint","typeoid":20,"value":"9223372036854775807"},
+                {"name":"doc","type":"jsonb","typeoid":3802,"value":{"a":1}},
+                {"name":"name","type":"text","typeoid":25,"value":null}
+            ]}"#,
+        ) {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        };
+        assert_eq!(insert.op, Op::Insert);
+        assert_eq!(insert.table, "public.tenants");
+        assert_eq!(insert.lsn, Lsn::new(0x0100));
+        assert_eq!(
+            insert
+                .new
+                .iter()
+                .map(|column| (column.name.as_str(), column.type_oid, column.value.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "id",
+                    Some(2950),
+                    Value::Uuid("c497c1be-cf70-41aa-8665-971e2ffaefcd".parse().unwrap())
+                ),
+                ("count", Some(23), Value::Int4(3)),
+                ("amount", Some(1700), Value::Numeric("1.50".to_string())),
+                (
+                    "total",
+                    Some(1700),
+                    Value::Numeric("12345678901234567890.123456789".to_string())
+                ),
+                ("big", Some(20), Value::Int8(i64::MAX)),
+                ("doc", Some(3802), Value::Json(serde_json::json!({"a": 1}))),
+                ("name", Some(25), Value::Null),
+            ]
+        );
+
+        let delete = match decode(
+            r#"{"action":"D","schema":"public","table":"tenants","identity":[
+                {"name":"id","type":"uuid","typeoid":2950,"value":"c497c1be-cf70-41aa-8665-971e2ffaefcd"}
+            ]}"#,
+        ) {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        };
+        assert_eq!(delete.op, Op::Delete);
+        assert!(delete.new.is_empty());
+        assert_eq!(delete.old[0].name, "id");
+
+        assert_eq!(
+            decode(r#"{"action":"C","xid":733,"timestamp":"2000-01-01 02:00:01.5+02"}"#),
+            Some(PluginMessage::Commit {
+                commit_time: Some(946_684_801_500_000),
+            })
+        );
+
+        assert!(Wal2JsonDecoder
+            .decode(Lsn::INVALID, Bytes::from_static(br#"{"action":"X"}"#))
+            .is_err());
+        assert!(Wal2JsonDecoder
+            .decode(Lsn::INVALID, Bytes::from_static(b"{"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_table() {
+        match decode(r#"{"action":"D","schema":"My Schema","table":"Tenant Events","identity":[]}"#)
+        {
+            Some(PluginMessage::Change(change)) => {
+                assert_eq!(change.table, "My Schema.Tenant Events")
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_decode_json_scalars() {
+        let insert = match decode(
+            r#"{"action":"I","schema":"public","table":"documents","columns":[
+                {"name":"name","type":"jsonb","typeoid":3802,"value":"abc"},
+                {"name":"flag","type":"jsonb","typeoid":3802,"value":true},
+                {"name":"count","type":"json","typeoid":114,"value":1.5},
+                {"name":"missing","type":"jsonb","typeoid":3802,"value":null}
+            ]}"#,
+        ) {
+            Some(PluginMessage::Change(change)) => change,
+            message => panic!("unexpected {:?}", message),
+        };
+        assert_eq!(
+            insert
+                .new
+                .into_iter()
+                .map(|column| column.value)
+                .collect::<Vec<_>>(),
+            vec![
+                Value::Json(serde_json::json!("abc")),
+                Value::Json(serde_json::json!(true)),
+                Value::Json(serde_json::json!(1.5)),
+                Value::Null,
+            ]
+        );
+    }
+}
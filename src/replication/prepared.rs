@@ -0,0 +1,175 @@
+use super::{Acker, Lsn, ReplicationError, Transaction, TransactionKind};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::trace;
+
+/// holds the changes of prepared transactions back until they are committed
+///
+/// for consumers which only act on committed changes such as `TableCache` and `Subscriptions`. the
+/// changes delivered with a `Prepare` are kept in memory until its `CommitPrepared` or
+/// `RollbackPrepared` arrives. as they would be lost on a restart, `ack` holds acknowledgements back
+/// until then so the server delivers the prepare again. clones share the same transactions.
+#[derive(Debug, Clone, Default)]
+pub struct PreparedTransactions {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// the prepared transactions by gid with the position acknowledgements are held at
+    transactions: HashMap<String, (Transaction, Lsn)>,
+    /// the commit lsn of the last transaction seen
+    last_commit: Lsn,
+}
+
+impl PreparedTransactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the transaction whose changes `transaction` commits, if any
+    ///
+    /// that is `transaction` itself unless two-phase, or the transaction prepared earlier for a
+    /// `CommitPrepared` with the commit's time and position. fails with `UnknownPrepared` if the
+    /// prepare of a `CommitPrepared` was never seen, e.g. because the consumer lagged behind.
+    pub fn committed<'a>(
+        &self,
+        transaction: &'a Transaction,
+    ) -> Result<Option<Cow<'a, Transaction>>, ReplicationError> {
+        let mut state = self.state.lock().unwrap();
+        let held_at = state.last_commit;
+        state.last_commit = state.last_commit.max(transaction.commit_lsn);
+        match &transaction.kind {
+            TransactionKind::Commit => Ok(Some(Cow::Borrowed(transaction))),
+            TransactionKind::Prepare { gid } => {
+                trace!("Holding back prepared transaction {:?}", gid);
+                state
+                    .transactions
+                    .insert(gid.clone(), (transaction.clone(), held_at));
+                Ok(None)
+            }
+            TransactionKind::CommitPrepared { gid } => match state.transactions.remove(gid) {
+                Some((prepared, _)) => Ok(Some(Cow::Owned(Transaction {
+                    commit_time: transaction.commit_time,
+                    commit_lsn: transaction.commit_lsn,
+                    header: transaction.header,
+                    kind: transaction.kind.clone(),
+                    ..prepared
+                }))),
+                None => Err(ReplicationError::UnknownPrepared(gid.clone())),
+            },
+            TransactionKind::RollbackPrepared { gid } => {
+                state.transactions.remove(gid);
+                Ok(None)
+            }
+        }
+    }
+
+    /// acknowledges `transaction` with `acker`, but only up to the last transaction seen before the
+    /// oldest prepared transaction which is neither committed nor rolled back yet
+    pub fn ack(&self, acker: &Acker, transaction: &Transaction) {
+        let state = self.state.lock().unwrap();
+        let held_at = state
+            .transactions
+            .values()
+            .map(|(_, held_at)| *held_at)
+            .min();
+        acker.ack_lsn(held_at.map_or(transaction.commit_lsn, |held_at| {
+            held_at.min(transaction.commit_lsn)
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::{
+        test_util::{column, row_change, transaction},
+        Acknowledgements, Change, Op, Value,
+    };
+
+    fn transaction_at(commit_lsn: u64, events: Vec<Change>, kind: TransactionKind) -> Transaction {
+        Transaction {
+            commit_lsn: Lsn::new(commit_lsn),
+            kind,
+            ..transaction(7, events)
+        }
+    }
+
+    #[test]
+    fn test_committed() -> Result<(), ReplicationError> {
+        let prepared = PreparedTransactions::new();
+        let change = row_change(
+            "public.tenants",
+            Op::Insert,
+            vec![column("id", Value::Int4(1))],
+        );
+        let gid = |gid: &str| gid.to_string();
+
+        let commit = transaction_at(100, vec![change.clone()], TransactionKind::Commit);
+        assert!(matches!(
+            prepared.committed(&commit)?,
+            Some(Cow::Borrowed(_))
+        ));
+
+        for gid in [gid("a"), gid("b")] {
+            let prepare =
+                transaction_at(200, vec![change.clone()], TransactionKind::Prepare { gid });
+            assert!(prepared.committed(&prepare)?.is_none());
+        }
+
+        let commit_prepared = transaction_at(
+            300,
+            vec![],
+            TransactionKind::CommitPrepared { gid: gid("a") },
+        );
+        let committed = prepared.committed(&commit_prepared)?.unwrap();
+        assert_eq!(committed.events.to_vec()?, vec![change]);
+        assert_eq!(committed.commit_lsn, Lsn::new(300));
+
+        let rollback_prepared = transaction_at(
+            400,
+            vec![],
+            TransactionKind::RollbackPrepared { gid: gid("b") },
+        );
+        assert!(prepared.committed(&rollback_prepared)?.is_none());
+        // both are forgotten once they ended
+        assert!(matches!(
+            prepared.committed(&commit_prepared),
+            Err(ReplicationError::UnknownPrepared(gid)) if gid == "a"
+        ));
+        assert!(prepared.state.lock().unwrap().transactions.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack() -> Result<(), ReplicationError> {
+        let prepared = PreparedTransactions::new();
+        let acknowledgements = Acknowledgements::new();
+        let acker = acknowledgements.register();
+        let process = |commit_lsn: u64, kind: TransactionKind| {
+            let transaction = transaction_at(commit_lsn, vec![], kind);
+            prepared.committed(&transaction)?;
+            prepared.ack(&acker, &transaction);
+            Ok::<_, ReplicationError>(acknowledgements.acknowledged())
+        };
+        let gid = |gid: &str| gid.to_string();
+
+        assert_eq!(process(100, TransactionKind::Commit)?, Some(Lsn::new(100)));
+
+        // nothing past the oldest prepare is acknowledged until it ends
+        let prepare = |gid| TransactionKind::Prepare { gid };
+        assert_eq!(process(200, prepare(gid("a")))?, Some(Lsn::new(100)));
+        assert_eq!(process(300, prepare(gid("b")))?, Some(Lsn::new(100)));
+        assert_eq!(process(400, TransactionKind::Commit)?, Some(Lsn::new(100)));
+
+        let commit_prepared = TransactionKind::CommitPrepared { gid: gid("b") };
+        assert_eq!(process(500, commit_prepared)?, Some(Lsn::new(100)));
+        let rollback_prepared = TransactionKind::RollbackPrepared { gid: gid("a") };
+        assert_eq!(process(600, rollback_prepared)?, Some(Lsn::new(600)));
+        Ok(())
+    }
+}
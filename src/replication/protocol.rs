@@ -0,0 +1,177 @@
+use super::{Lsn, ReplicationError};
+use bytes::{Buf, Bytes};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static MICROSECONDS_FROM_UNIX_EPOCH_TO_2000: u64 = 946_684_800_000_000;
+
+/// converts a `SystemTime` to a server timestamp (microseconds since 2000-01-01)
+pub(crate) fn to_pg_timestamp(time: SystemTime) -> i64 {
+    let unix_micros = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_micros() as i64,
+        Err(err) => -(err.duration().as_micros() as i64),
+    };
+    unix_micros - MICROSECONDS_FROM_UNIX_EPOCH_TO_2000 as i64
+}
+
+/// converts a server timestamp (microseconds since 2000-01-01) to a `SystemTime`
+pub(crate) fn from_pg_timestamp(micros: i64) -> SystemTime {
+    let unix_micros = micros + MICROSECONDS_FROM_UNIX_EPOCH_TO_2000 as i64;
+    if unix_micros >= 0 {
+        UNIX_EPOCH + Duration::from_micros(unix_micros as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_micros(unix_micros.unsigned_abs())
+    }
+}
+
+/// the header of an XLogData ('w') message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XLogDataHeader {
+    /// the starting point of the wal data in this message
+    pub wal_start: Lsn,
+    /// the current end of wal on the server
+    pub wal_end: Lsn,
+    /// the server's system clock at the time of transmission
+    pub send_time: SystemTime,
+}
+
+/// a message carrying output plugin data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XLogData {
+    pub header: XLogDataHeader,
+    pub data: Bytes,
+}
+
+/// a primary keepalive ('k') message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrimaryKeepalive {
+    /// the current end of wal on the server
+    pub wal_end: Lsn,
+    /// the server's system clock at the time of transmission
+    pub send_time: SystemTime,
+    /// whether the server asks for a status update soon to avoid a timeout disconnect
+    pub reply_requested: bool,
+}
+
+/// a message sent by the server inside the `START_REPLICATION` copy stream
+///
+/// see here for format details: https://www.postgresql.org/docs/current/protocol-replication.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationMessage {
+    XLogData(XLogData),
+    PrimaryKeepalive(PrimaryKeepalive),
+}
+
+impl ReplicationMessage {
+    pub fn parse(mut frame: Bytes) -> Result<Self, ReplicationError> {
+        if frame.is_empty() {
+            return Err(ReplicationError::Protocol("empty message".to_string()));
+        }
+
+        match frame.get_u8() {
+            b'w' => {
+                ensure_remaining(&frame, 24, "XLogData")?;
+                Ok(ReplicationMessage::XLogData(XLogData {
+                    header: XLogDataHeader {
+                        wal_start: Lsn::new(frame.get_u64()),
+                        wal_end: Lsn::new(frame.get_u64()),
+                        send_time: from_pg_timestamp(frame.get_i64()),
+                    },
+                    data: frame,
+                }))
+            }
+            b'k' => {
+                ensure_remaining(&frame, 17, "PrimaryKeepalive")?;
+                let keepalive = PrimaryKeepalive {
+                    wal_end: Lsn::new(frame.get_u64()),
+                    send_time: from_pg_timestamp(frame.get_i64()),
+                    reply_requested: frame.get_u8() == 1,
+                };
+                Ok(ReplicationMessage::PrimaryKeepalive(keepalive))
+            }
+            tag => Err(ReplicationError::Protocol(format!(
+                "unknown message type: {:?}",
+                tag as char
+            ))),
+        }
+    }
+}
+
+fn ensure_remaining(frame: &Bytes, len: usize, message: &str) -> Result<(), ReplicationError> {
+    if frame.remaining() < len {
+        return Err(ReplicationError::Protocol(format!(
+            "{} message truncated: expected at least {} bytes but got {}",
+            message,
+            len,
+            frame.remaining()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn test_parse_xlogdata() {
+        let mut frame = BytesMut::new();
+        frame.put_u8(b'w');
+        frame.put_u64(0x16_B374_D848);
+        frame.put_u64(0x16_B374_D900);
+        frame.put_i64(0);
+        frame.put_slice(b"payload");
+
+        let message = ReplicationMessage::parse(frame.freeze()).unwrap();
+        assert_eq!(
+            message,
+            ReplicationMessage::XLogData(XLogData {
+                header: XLogDataHeader {
+                    wal_start: Lsn::new(0x16_B374_D848),
+                    wal_end: Lsn::new(0x16_B374_D900),
+                    send_time: UNIX_EPOCH + Duration::from_secs(946_684_800),
+                },
+                data: Bytes::from_static(b"payload"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_keepalive() {
+        let mut frame = BytesMut::new();
+        frame.put_u8(b'k');
+        frame.put_u64(42);
+        frame.put_i64(1_000_000);
+        frame.put_u8(1);
+
+        let message = ReplicationMessage::parse(frame.freeze()).unwrap();
+        assert_eq!(
+            message,
+            ReplicationMessage::PrimaryKeepalive(PrimaryKeepalive {
+                wal_end: Lsn::new(42),
+                send_time: UNIX_EPOCH + Duration::from_secs(946_684_801),
+                reply_requested: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert!(matches!(
+            ReplicationMessage::parse(Bytes::new()),
+            Err(ReplicationError::Protocol(_))
+        ));
+        assert!(matches!(
+            ReplicationMessage::parse(Bytes::from_static(b"w\x00\x01")),
+            Err(ReplicationError::Protocol(_))
+        ));
+        assert!(matches!(
+            ReplicationMessage::parse(Bytes::from_static(b"k")),
+            Err(ReplicationError::Protocol(_))
+        ));
+        assert!(matches!(
+            ReplicationMessage::parse(Bytes::from_static(b"x")),
+            Err(ReplicationError::Protocol(_))
+        ));
+    }
+}
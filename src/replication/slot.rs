@@ -0,0 +1,157 @@
+use super::{Lsn, OutputPlugin, ReplicationConfig, ReplicationError};
+use tokio_postgres::{Client, SimpleQueryMessage, SimpleQueryRow};
+use tracing::debug;
+
+/// a replication slot ready to be streamed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Slot {
+    pub name: String,
+    /// the position to issue `START_REPLICATION` from
+    pub start_lsn: Lsn,
+    /// the snapshot exported when the slot was created with `EXPORT_SNAPSHOT`
+    pub snapshot_name: Option<String>,
+}
+
+/// creates the configured replication slot or, for persistent slots, reuses it if it already exists.
+///
+/// a reused slot resumes from its `confirmed_flush_lsn` so no changes are lost between restarts.
+/// `export_snapshot` requires the slot to be created as the snapshot is only exported on creation.
+pub(crate) async fn create_or_reuse_slot(
+    client: &Client,
+    config: &ReplicationConfig,
+    plugin: &dyn OutputPlugin,
+    export_snapshot: bool,
+) -> Result<Slot, ReplicationError> {
+    if !config.temporary_slot && config.slot_name.is_none() {
+        return Err(ReplicationError::Config(
+            "persistent replication slots require a slot_name".to_string(),
+        ));
+    }
+    let name = config.slot_name_or_generate();
+    let slot_error = |message: String| ReplicationError::Slot {
+        slot_name: name.clone(),
+        message,
+        source: None,
+    };
+    let query_error = |err: tokio_postgres::Error| ReplicationError::Slot {
+        slot_name: name.clone(),
+        message: err.to_string(),
+        source: Some(err),
+    };
+
+    if !config.temporary_slot {
+        let existing_query = format!(
+            "SELECT slot_type, database = current_database() AS same_database, plugin, confirmed_flush_lsn \
+             FROM pg_replication_slots WHERE slot_name = '{}'",
+            name.replace('\'', "''")
+        );
+
+        let existing = client
+            .simple_query(&existing_query)
+            .await
+            .map_err(query_error)?;
+
+        if let Some(row) = first_row(existing) {
+            if export_snapshot {
+                return Err(slot_error(
+                    "already exists so no snapshot can be exported".to_string(),
+                ));
+            }
+
+            // slot names are unique across databases and slot types
+            let slot_type = row.get("slot_type").unwrap_or_default();
+            if slot_type != "logical" {
+                return Err(slot_error(format!(
+                    "is a {} slot rather than a logical one",
+                    slot_type
+                )));
+            }
+            if row.get("same_database") != Some("t") {
+                return Err(slot_error(format!(
+                    "belongs to another database than {:?}",
+                    config.dbname
+                )));
+            }
+
+            let existing_plugin = row.get("plugin").unwrap_or_default();
+            if existing_plugin != plugin.name() {
+                return Err(slot_error(format!(
+                    "created with plugin {:?} but {:?} is configured",
+                    existing_plugin,
+                    plugin.name()
+                )));
+            }
+
+            let confirmed_flush_lsn = row
+                .get("confirmed_flush_lsn")
+                .ok_or_else(|| slot_error("has no confirmed_flush_lsn".to_string()))?
+                .parse::<Lsn>()
+                .map_err(|err| slot_error(err.to_string()))?;
+            debug!(
+                "Reusing replication slot {} at confirmed_flush_lsn {}",
+                name, confirmed_flush_lsn
+            );
+
+            return Ok(Slot {
+                start_lsn: config.start_lsn.unwrap_or(confirmed_flush_lsn),
+                name,
+                snapshot_name: None,
+            });
+        }
+    }
+
+    let mut slot_query = format!(
+        "CREATE_REPLICATION_SLOT {} {}LOGICAL \"{}\"",
+        name,
+        if config.temporary_slot {
+            "TEMPORARY "
+        } else {
+            ""
+        },
+        plugin.name()
+    );
+    if export_snapshot {
+        slot_query += " EXPORT_SNAPSHOT";
+    }
+    if let Some(options) = plugin.create_slot_options() {
+        slot_query = format!("{} {}", slot_query, options);
+    }
+
+    let created = client
+        .simple_query(&slot_query)
+        .await
+        .map_err(query_error)?;
+    let created = first_row(created).ok_or_else(|| slot_error("no slot returned".to_string()))?;
+    let consistent_point = created
+        .get("consistent_point")
+        .ok_or_else(|| slot_error("no consistent_point returned".to_string()))?
+        .parse::<Lsn>()
+        .map_err(|err| slot_error(err.to_string()))?;
+    debug!(
+        "Created replication slot {} at consistent_point {}",
+        name, consistent_point
+    );
+
+    let snapshot_name = match export_snapshot {
+        true => Some(
+            created
+                .get("snapshot_name")
+                .ok_or_else(|| slot_error("no snapshot_name returned".to_string()))?
+                .to_string(),
+        ),
+        false => None,
+    };
+
+    Ok(Slot {
+        start_lsn: config.start_lsn.unwrap_or(consistent_point),
+        name,
+        snapshot_name,
+    })
+}
+
+fn first_row(messages: Vec<SimpleQueryMessage>) -> Option<SimpleQueryRow> {
+    messages.into_iter().find_map(|msg| match msg {
+        SimpleQueryMessage::Row(row) => Some(row),
+        _ => None,
+    })
+}
@@ -0,0 +1,166 @@
+use super::{Change, Column, Lsn, Op, ReplicationConfig, ReplicationError, Value};
+use std::collections::HashMap;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+use tracing::debug;
+
+/// the snapshot exported by a newly created replication slot
+///
+/// the snapshot sees exactly the data committed before the slot's `consistent_point` so tables loaded
+/// in it and the changes streamed afterwards neither overlap nor leave a gap. it is only valid until
+/// streaming starts. other connections can import it with
+/// `BEGIN ISOLATION LEVEL REPEATABLE READ; SET TRANSACTION SNAPSHOT '<name>'` to run their own queries.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// the identifier of the exported snapshot
+    pub name: String,
+    /// the position streaming continues from once the snapshot is loaded
+    pub consistent_point: Lsn,
+    client: Client,
+}
+
+impl Snapshot {
+    /// opens a connection with a read only transaction importing the snapshot
+    pub(crate) async fn import(
+        config: &ReplicationConfig,
+        name: String,
+        consistent_point: Lsn,
+    ) -> Result<Self, ReplicationError> {
+        let (client, connection) =
+            tokio_postgres::connect(&config.connection_string(false), NoTls).await?;
+        tokio::spawn(connection);
+
+        client
+            .batch_execute(&format!(
+                "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SET TRANSACTION SNAPSHOT '{}'",
+                name.replace('\'', "''")
+            ))
+            .await?;
+        debug!("Imported snapshot {} at {}", name, consistent_point);
+
+        Ok(Self {
+            name,
+            consistent_point,
+            client,
+        })
+    }
+
+    /// opens a connection with a read only transaction taking a snapshot of the current data
+    ///
+    /// unlike the snapshot exported by a new slot it does not line up with a position of the
+    /// replication stream. `consistent_point` is the end of the wal when the snapshot was taken so
+    /// every transaction visible in it committed before that position.
+    pub async fn current(config: &ReplicationConfig) -> Result<Self, ReplicationError> {
+        let (client, connection) =
+            tokio_postgres::connect(&config.connection_string(false), NoTls).await?;
+        tokio::spawn(connection);
+
+        // the snapshot is taken by the first query so the insert position is read after it
+        let row = client
+            .simple_query(
+                "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SELECT pg_export_snapshot() AS name, pg_current_wal_insert_lsn()::TEXT AS lsn",
+            )
+            .await?
+            .into_iter()
+            .find_map(|message| match message {
+                SimpleQueryMessage::Row(row) => Some(row),
+                _ => None,
+            })
+            .ok_or_else(|| ReplicationError::Protocol("no snapshot returned".to_string()))?;
+
+        let name = row.get("name").unwrap_or_default().to_string();
+        let consistent_point = row
+            .get("lsn")
+            .unwrap_or_default()
+            .parse::<Lsn>()
+            .map_err(|err| ReplicationError::Protocol(err.to_string()))?;
+        debug!("Took snapshot {} at {}", name, consistent_point);
+
+        Ok(Self {
+            name,
+            consistent_point,
+            client,
+        })
+    }
+
+    /// every row of the schema qualified `table` as seen by the snapshot, as inserts
+    pub async fn changes(&self, table: &str) -> Result<Vec<Change>, ReplicationError> {
+        let table_ident = quote_table(table);
+
+        let type_oids = self
+            .client
+            .simple_query(&format!(
+                "SELECT attname, atttypid FROM pg_attribute WHERE attrelid = '{}'::regclass AND attnum > 0 AND NOT attisdropped",
+                table_ident.replace('\'', "''")
+            ))
+            .await?
+            .into_iter()
+            .filter_map(|message| match message {
+                SimpleQueryMessage::Row(row) => Some((
+                    row.get("attname").unwrap_or_default().to_string(),
+                    row.get("atttypid").and_then(|oid| oid.parse::<u32>().ok()),
+                )),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let rows = self
+            .client
+            .simple_query(&format!("SELECT * FROM {}", table_ident))
+            .await?;
+
+        rows.into_iter()
+            .filter_map(|message| match message {
+                SimpleQueryMessage::Row(row) => Some(row),
+                _ => None,
+            })
+            .map(|row| {
+                let new = row
+                    .columns()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| {
+                        let type_oid = type_oids.get(column.name()).copied().flatten();
+                        Ok(Column {
+                            name: column.name().to_string(),
+                            type_oid,
+                            value: match row.get(i) {
+                                Some(text) => Value::from_text(type_oid, text)?,
+                                None => Value::Null,
+                            },
+                        })
+                    })
+                    .collect::<Result<Vec<_>, ReplicationError>>()?;
+
+                Ok(Change {
+                    table: table.to_string(),
+                    op: Op::Insert,
+                    old: vec![],
+                    new,
+                    lsn: self.consistent_point,
+                    truncate: None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// quotes a possibly schema qualified table name as an identifier
+fn quote_table(table: &str) -> String {
+    table
+        .splitn(2, '.')
+        .map(|ident| format!("\"{}\"", ident.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quote_table() {
+        assert_eq!(quote_table("public.tenants"), "\"public\".\"tenants\"");
+        assert_eq!(quote_table("tenants"), "\"tenants\"");
+        assert_eq!(quote_table("odd.\"name.s"), "\"odd\".\"\"\"name.s\"");
+    }
+}
@@ -0,0 +1,739 @@
+use super::{Change, Column, Lsn, Op, ReplicationError, Truncate, Value};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Datelike, NaiveDate};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+/// how much of a spill file is read at once while iterating its changes
+const READ_CHUNK: usize = 64 * 1024;
+
+/// the changes of a transaction being received
+///
+/// once the estimated size of the buffered changes exceeds the threshold they are written to an
+/// anonymous temporary file, which is removed once the last `Events` reading it drops.
+///
+/// every change is tagged with the xid of the (sub)transaction that made it so the changes of an
+/// aborted subtransaction can be discarded, even once spilled.
+#[derive(Debug, Default)]
+pub(crate) struct ChangeBuffer {
+    threshold: Option<usize>,
+    directory: Option<PathBuf>,
+    changes: Vec<(u32, Change)>,
+    /// the estimated size of `changes`
+    size: usize,
+    spilled: Option<BufWriter<File>>,
+    /// the number of spilled changes of each (sub)transaction
+    spilled_counts: HashMap<u32, usize>,
+    /// subtransactions whose spilled changes are skipped when read back
+    discarded: HashSet<u32>,
+}
+
+impl ChangeBuffer {
+    pub fn new(threshold: Option<usize>, directory: Option<PathBuf>) -> Self {
+        Self {
+            threshold,
+            directory,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, xid: u32, change: Change) -> Result<(), ReplicationError> {
+        self.size += estimated_size(&change);
+        self.changes.push((xid, change));
+
+        match self.threshold {
+            Some(threshold) if self.size > threshold => self.spill(),
+            _ => Ok(()),
+        }
+    }
+
+    /// drops the changes of the (sub)transaction `xid`
+    pub fn discard(&mut self, xid: u32) {
+        self.changes.retain(|(change_xid, _)| *change_xid != xid);
+        self.size = self
+            .changes
+            .iter()
+            .map(|(_, change)| estimated_size(change))
+            .sum();
+        if self.spilled_counts.contains_key(&xid) {
+            self.discarded.insert(xid);
+        }
+    }
+
+    /// writes the buffered changes to the temporary file
+    fn spill(&mut self) -> Result<(), ReplicationError> {
+        let file = match &mut self.spilled {
+            Some(file) => file,
+            None => {
+                let file = match &self.directory {
+                    Some(directory) => tempfile::tempfile_in(directory)?,
+                    None => tempfile::tempfile()?,
+                };
+                self.spilled.insert(BufWriter::new(file))
+            }
+        };
+
+        debug!(
+            "Spilling {} changes of {} bytes to disk",
+            self.changes.len(),
+            self.size
+        );
+        let mut buf = BytesMut::new();
+        self.size = 0;
+        for (xid, change) in self.changes.drain(..) {
+            *self.spilled_counts.entry(xid).or_default() += 1;
+            buf.clear();
+            buf.put_u32(xid);
+            put_change(&mut buf, &change);
+            file.write_all(&(buf.len() as u32).to_be_bytes())?;
+            file.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// every change not discarded in the order pushed, those spilled are left on disk
+    pub fn into_events(mut self) -> Result<Events, ReplicationError> {
+        let spilled = match self.spilled.take() {
+            Some(file) => {
+                let mut file = file.into_inner().map_err(io::IntoInnerError::into_error)?;
+                let len = file.stream_position()?;
+                let count = self
+                    .spilled_counts
+                    .iter()
+                    .filter(|(xid, _)| !self.discarded.contains(xid))
+                    .map(|(_, count)| count)
+                    .sum();
+                Some(Arc::new(Spilled {
+                    file: Mutex::new(file),
+                    len,
+                    count,
+                    discarded: self.discarded,
+                }))
+            }
+            None => None,
+        };
+
+        Ok(Events {
+            spilled,
+            changes: self.changes.into_iter().map(|(_, change)| change).collect(),
+        })
+    }
+}
+
+/// the changes of a transaction in the order they were made
+///
+/// the changes of a transaction too large to keep in memory are spilled to disk, see
+/// `ReplicationConfig::spill_threshold`, and only read back a chunk at a time while iterating so
+/// consumers should iterate rather than collect them. clones share the spilled changes.
+#[derive(Clone, Default)]
+pub struct Events {
+    spilled: Option<Arc<Spilled>>,
+    /// the changes made after the last spill
+    changes: Vec<Change>,
+}
+
+impl Events {
+    /// the number of changes
+    pub fn len(&self) -> usize {
+        self.spilled.as_ref().map_or(0, |spilled| spilled.count) + self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// iterates over the changes, reading those spilled back from disk which may fail
+    pub fn iter(&self) -> impl Iterator<Item = Result<Cow<'_, Change>, ReplicationError>> {
+        self.spilled
+            .iter()
+            .flat_map(|spilled| SpilledChanges::new(spilled))
+            .map(|change| change.map(Cow::Owned))
+            .chain(self.changes.iter().map(|change| Ok(Cow::Borrowed(change))))
+    }
+
+    /// every change in memory at once
+    pub fn to_vec(&self) -> Result<Vec<Change>, ReplicationError> {
+        self.iter()
+            .map(|change| change.map(Cow::into_owned))
+            .collect()
+    }
+}
+
+impl From<Vec<Change>> for Events {
+    fn from(changes: Vec<Change>) -> Self {
+        Self {
+            spilled: None,
+            changes,
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field(
+                "spilled",
+                &self.spilled.as_ref().map_or(0, |spilled| spilled.count),
+            )
+            .field("changes", &self.changes)
+            .finish()
+    }
+}
+
+/// the changes written to a spill file
+#[derive(Debug)]
+struct Spilled {
+    /// shared by every iteration, which each seek to their own position
+    file: Mutex<File>,
+    /// the length of the file
+    len: u64,
+    /// the number of changes not discarded
+    count: usize,
+    /// subtransactions whose changes are skipped
+    discarded: HashSet<u32>,
+}
+
+/// reads the changes of a spill file back a chunk at a time
+struct SpilledChanges<'a> {
+    spilled: &'a Spilled,
+    /// the position in the file of the next chunk
+    offset: u64,
+    buf: BytesMut,
+}
+
+impl<'a> SpilledChanges<'a> {
+    fn new(spilled: &'a Spilled) -> Self {
+        Self {
+            spilled,
+            offset: 0,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// the next record of a xid and change, or `None` at the end of the file
+    fn record(&mut self) -> Result<Option<Reader>, ReplicationError> {
+        if !self.fill(4)? {
+            return match self.buf.is_empty() {
+                true => Ok(None),
+                false => Err(invalid("spilled change truncated")),
+            };
+        }
+        let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+        if !self.fill(4 + len)? {
+            return Err(invalid("spilled change truncated"));
+        }
+        self.buf.advance(4);
+        Ok(Some(Reader(self.buf.split_to(len).freeze())))
+    }
+
+    /// the next change not discarded
+    fn change(&mut self) -> Result<Option<Change>, ReplicationError> {
+        while let Some(mut record) = self.record()? {
+            if !self.spilled.discarded.contains(&record.u32()?) {
+                return record.change().map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// reads the file until at least `len` bytes are buffered, `false` if it ends before
+    fn fill(&mut self, len: usize) -> Result<bool, ReplicationError> {
+        while self.buf.len() < len {
+            let remaining = (self.spilled.len - self.offset) as usize;
+            if remaining == 0 {
+                return Ok(false);
+            }
+            let chunk = (len - self.buf.len()).max(READ_CHUNK).min(remaining);
+            let start = self.buf.len();
+            self.buf.resize(start + chunk, 0);
+
+            let mut file = self.spilled.file.lock().unwrap();
+            file.seek(SeekFrom::Start(self.offset))?;
+            file.read_exact(&mut self.buf[start..])?;
+            self.offset += chunk as u64;
+        }
+        Ok(true)
+    }
+}
+
+impl Iterator for SpilledChanges<'_> {
+    type Item = Result<Change, ReplicationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.change() {
+            Ok(change) => change.map(Ok),
+            Err(err) => {
+                // a corrupt file ends the iteration
+                self.offset = self.spilled.len;
+                self.buf.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// the approximate memory used by a change
+fn estimated_size(change: &Change) -> usize {
+    mem::size_of::<Change>()
+        + change.table.len()
+        + change
+            .truncate
+            .iter()
+            .flat_map(|truncate| &truncate.tables)
+            .map(|table| mem::size_of::<String>() + table.len())
+            .sum::<usize>()
+        + change
+            .old
+            .iter()
+            .chain(&change.new)
+            .map(|column| mem::size_of::<Column>() + column.name.len() + value_size(&column.value))
+            .sum::<usize>()
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Numeric(value) | Value::Text(value) => value.len(),
+        Value::Bytea(value) => value.len(),
+        Value::Json(value) => json_size(value),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| mem::size_of::<Value>() + value_size(value))
+            .sum(),
+        _ => 0,
+    }
+}
+
+fn json_size(value: &serde_json::Value) -> usize {
+    mem::size_of::<serde_json::Value>()
+        + match value {
+            serde_json::Value::String(value) => value.len(),
+            serde_json::Value::Array(values) => values.iter().map(json_size).sum(),
+            serde_json::Value::Object(values) => values
+                .iter()
+                .map(|(key, value)| key.len() + json_size(value))
+                .sum(),
+            _ => 0,
+        }
+}
+
+fn put_str(buf: &mut BytesMut, value: &str) {
+    buf.put_u32(value.len() as u32);
+    buf.put_slice(value.as_bytes());
+}
+
+fn put_change(buf: &mut BytesMut, change: &Change) {
+    put_str(buf, &change.table);
+    buf.put_u8(match change.op {
+        Op::Insert => b'I',
+        Op::Update => b'U',
+        Op::Delete => b'D',
+        Op::Truncate => b'T',
+    });
+    buf.put_u64(change.lsn.as_u64());
+    for columns in [&change.old, &change.new] {
+        buf.put_u32(columns.len() as u32);
+        for column in columns {
+            put_str(buf, &column.name);
+            match column.type_oid {
+                Some(type_oid) => {
+                    buf.put_u8(1);
+                    buf.put_u32(type_oid);
+                }
+                None => buf.put_u8(0),
+            }
+            put_value(buf, &column.value);
+        }
+    }
+    match &change.truncate {
+        Some(truncate) => {
+            buf.put_u8(1);
+            buf.put_u32(truncate.tables.len() as u32);
+            for table in &truncate.tables {
+                put_str(buf, table);
+            }
+            buf.put_u8(truncate.cascade as u8);
+            buf.put_u8(truncate.restart_identity as u8);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+fn put_value(buf: &mut BytesMut, value: &Value) {
+    match value {
+        Value::Null => buf.put_u8(0),
+        Value::UnchangedToast => buf.put_u8(1),
+        Value::Bool(value) => {
+            buf.put_u8(2);
+            buf.put_u8(*value as u8);
+        }
+        Value::Int2(value) => {
+            buf.put_u8(3);
+            buf.put_i16(*value);
+        }
+        Value::Int4(value) => {
+            buf.put_u8(4);
+            buf.put_i32(*value);
+        }
+        Value::Int8(value) => {
+            buf.put_u8(5);
+            buf.put_i64(*value);
+        }
+        Value::Float(value) => {
+            buf.put_u8(6);
+            buf.put_f64(*value);
+        }
+        Value::Numeric(value) => {
+            buf.put_u8(7);
+            put_str(buf, value);
+        }
+        Value::Text(value) => {
+            buf.put_u8(8);
+            put_str(buf, value);
+        }
+        Value::Uuid(value) => {
+            buf.put_u8(9);
+            buf.put_slice(value.as_bytes());
+        }
+        Value::Bytea(value) => {
+            buf.put_u8(10);
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+        }
+        Value::Timestamp(value) => {
+            buf.put_u8(11);
+            let value = value.and_utc();
+            buf.put_i64(value.timestamp());
+            buf.put_u32(value.timestamp_subsec_nanos());
+        }
+        Value::Timestamptz(value) => {
+            buf.put_u8(12);
+            buf.put_i64(value.timestamp());
+            buf.put_u32(value.timestamp_subsec_nanos());
+        }
+        Value::Date(value) => {
+            buf.put_u8(13);
+            buf.put_i32(value.num_days_from_ce());
+        }
+        Value::Json(value) => {
+            buf.put_u8(14);
+            put_str(buf, &value.to_string());
+        }
+        Value::Point { x, y } => {
+            buf.put_u8(15);
+            buf.put_f64(*x);
+            buf.put_f64(*y);
+        }
+        Value::Array(values) => {
+            buf.put_u8(16);
+            buf.put_u32(values.len() as u32);
+            for value in values {
+                put_value(buf, value);
+            }
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> ReplicationError {
+    io::Error::new(io::ErrorKind::InvalidData, message.into()).into()
+}
+
+/// reads a spilled change failing instead of panicking on a corrupt file
+struct Reader(Bytes);
+
+impl Reader {
+    fn ensure(&self, len: usize) -> Result<(), ReplicationError> {
+        match self.0.remaining() < len {
+            true => Err(invalid("spilled change truncated")),
+            false => Ok(()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, ReplicationError> {
+        self.ensure(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn u32(&mut self) -> Result<u32, ReplicationError> {
+        self.ensure(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    fn i64(&mut self) -> Result<i64, ReplicationError> {
+        self.ensure(8)?;
+        Ok(self.0.get_i64())
+    }
+
+    fn f64(&mut self) -> Result<f64, ReplicationError> {
+        self.ensure(8)?;
+        Ok(self.0.get_f64())
+    }
+
+    fn bytes(&mut self) -> Result<Bytes, ReplicationError> {
+        let len = self.u32()? as usize;
+        self.ensure(len)?;
+        Ok(self.0.split_to(len))
+    }
+
+    fn string(&mut self) -> Result<String, ReplicationError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|err| invalid(err.to_string()))
+    }
+
+    fn change(&mut self) -> Result<Change, ReplicationError> {
+        let table = self.string()?;
+        let op = match self.u8()? {
+            b'I' => Op::Insert,
+            b'U' => Op::Update,
+            b'D' => Op::Delete,
+            b'T' => Op::Truncate,
+            op => return Err(invalid(format!("unknown op {}", op))),
+        };
+        let lsn = Lsn::new(self.i64()? as u64);
+        let old = self.columns()?;
+        let new = self.columns()?;
+        let truncate = match self.u8()? {
+            0 => None,
+            _ => Some(Truncate {
+                tables: (0..self.u32()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?,
+                cascade: self.u8()? != 0,
+                restart_identity: self.u8()? != 0,
+            }),
+        };
+        Ok(Change {
+            table,
+            op,
+            old,
+            new,
+            lsn,
+            truncate,
+        })
+    }
+
+    fn columns(&mut self) -> Result<Vec<Column>, ReplicationError> {
+        (0..self.u32()?)
+            .map(|_| {
+                Ok(Column {
+                    name: self.string()?,
+                    type_oid: match self.u8()? {
+                        0 => None,
+                        _ => Some(self.u32()?),
+                    },
+                    value: self.value()?,
+                })
+            })
+            .collect()
+    }
+
+    fn timestamp(&mut self) -> Result<DateTime<chrono::Utc>, ReplicationError> {
+        let secs = self.i64()?;
+        let nanos = self.u32()?;
+        DateTime::from_timestamp(secs, nanos).ok_or_else(|| invalid("timestamp out of range"))
+    }
+
+    fn value(&mut self) -> Result<Value, ReplicationError> {
+        let value = match self.u8()? {
+            0 => Value::Null,
+            1 => Value::UnchangedToast,
+            2 => Value::Bool(self.u8()? != 0),
+            3 => {
+                self.ensure(2)?;
+                Value::Int2(self.0.get_i16())
+            }
+            4 => {
+                self.ensure(4)?;
+                Value::Int4(self.0.get_i32())
+            }
+            5 => Value::Int8(self.i64()?),
+            6 => Value::Float(self.f64()?),
+            7 => Value::Numeric(self.string()?),
+            8 => Value::Text(self.string()?),
+            9 => {
+                self.ensure(16)?;
+                let mut bytes = [0; 16];
+                self.0.copy_to_slice(&mut bytes);
+                Value::Uuid(Uuid::from_bytes(bytes))
+            }
+            10 => Value::Bytea(self.bytes()?),
+            11 => Value::Timestamp(self.timestamp()?.naive_utc()),
+            12 => Value::Timestamptz(self.timestamp()?),
+            13 => {
+                self.ensure(4)?;
+                let days = self.0.get_i32();
+                Value::Date(
+                    NaiveDate::from_num_days_from_ce_opt(days)
+                        .ok_or_else(|| invalid("date out of range"))?,
+                )
+            }
+            14 => Value::Json(
+                serde_json::from_str(&self.string()?).map_err(|err| invalid(err.to_string()))?,
+            ),
+            15 => Value::Point {
+                x: self.f64()?,
+                y: self.f64()?,
+            },
+            16 => Value::Array(
+                (0..self.u32()?)
+                    .map(|_| self.value())
+                    .collect::<Result<_, _>>()?,
+            ),
+            tag => return Err(invalid(format!("unknown value tag {}", tag))),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::test_util;
+    use chrono::{NaiveDateTime, Utc};
+
+    fn change(id: i32) -> Change {
+        let column = |name: &str, value: Value| Column {
+            type_oid: Some(25),
+            ..test_util::column(name, value)
+        };
+        let change = test_util::change(
+            "public.tenants",
+            Op::Update,
+            vec![column("id", Value::Int4(id))],
+            vec![
+                column("id", Value::Int4(id)),
+                column("name", Value::Text("tenant".to_string())),
+                column("missing", Value::UnchangedToast),
+                column("deleted", Value::Null),
+                column("flag", Value::Bool(true)),
+                column("small", Value::Int2(-2)),
+                column("big", Value::Int8(i64::MAX)),
+                column("ratio", Value::Float(f64::INFINITY)),
+                column("amount", Value::Numeric("1.50".to_string())),
+                column("uuid", Value::Uuid(Uuid::from_u128(id as u128))),
+                column("bytes", Value::Bytea(Bytes::from_static(b"\x00\xff"))),
+                column("at", Value::Timestamp(NaiveDateTime::MAX)),
+                column("at_tz", Value::Timestamptz(DateTime::<Utc>::MIN_UTC)),
+                column("day", Value::Date(NaiveDate::MIN)),
+                column("json", Value::Json(serde_json::json!({"a": [1, null]}))),
+                column("point", Value::Point { x: 1.5, y: -2.0 }),
+                column(
+                    "tags",
+                    Value::Array(vec![Value::Text("a".to_string()), Value::Null]),
+                ),
+            ],
+        );
+        Change {
+            lsn: Lsn::new(id as u64),
+            // not a truncate but covers its encoding
+            truncate: Some(Truncate {
+                tables: vec!["public.tenants".to_string(), "public.audit".to_string()],
+                cascade: true,
+                restart_identity: false,
+            }),
+            ..change
+        }
+    }
+
+    #[test]
+    fn test_spill() -> Result<(), ReplicationError> {
+        let mut buffer = ChangeBuffer::new(Some(estimated_size(&change(0)) * 2), None);
+        for id in 0..5 {
+            buffer.push(1, change(id))?;
+        }
+        assert!(buffer.spilled.is_some());
+        assert_eq!(buffer.spilled_counts[&1], 3);
+        // the changes pushed since the threshold was last exceeded stay in memory
+        assert_eq!(buffer.changes.len(), 2);
+
+        let events = buffer.into_events()?;
+        assert_eq!(events.len(), 5);
+        assert_eq!(events.changes.len(), 2);
+        assert_eq!(events.to_vec()?, (0..5).map(change).collect::<Vec<_>>());
+        // clones read the same file independently
+        let (clone, mut events) = (events.clone(), events.iter());
+        for change in clone.iter() {
+            assert_eq!(change?, events.next().unwrap()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_threshold() -> Result<(), ReplicationError> {
+        let mut buffer = ChangeBuffer::new(None, None);
+        buffer.push(1, change(1))?;
+        assert!(buffer.spilled.is_none());
+        assert_eq!(buffer.into_events()?.to_vec()?, vec![change(1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard() -> Result<(), ReplicationError> {
+        let mut buffer = ChangeBuffer::new(Some(estimated_size(&change(0)) * 2), None);
+        // the subtransaction 2 is interleaved with its parent 1, partly spilled and partly in memory
+        for (xid, id) in [(1, 0), (2, 1), (1, 2), (2, 3), (1, 4)] {
+            buffer.push(xid, change(id))?;
+        }
+        assert_eq!(buffer.spilled_counts.values().sum::<usize>(), 3);
+
+        buffer.discard(2);
+        let events = buffer.into_events()?;
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events.to_vec()?,
+            [0, 2, 4].into_iter().map(change).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_memory() -> Result<(), ReplicationError> {
+        let threshold = estimated_size(&change(0)) * 10;
+        let mut buffer = ChangeBuffer::new(Some(threshold), None);
+        for id in 0..10_000 {
+            buffer.push(1, change(id))?;
+            assert!(buffer.size <= threshold);
+        }
+
+        // the spill file is many times larger than what is held in memory while reading it
+        let events = buffer.into_events()?;
+        assert!(events.changes.len() <= 10);
+        let spilled = events.spilled.as_ref().unwrap();
+        assert!(spilled.len > 50 * READ_CHUNK as u64);
+
+        let mut changes = SpilledChanges::new(spilled);
+        let mut id = 0;
+        while let Some(change) = changes.next() {
+            assert_eq!(change?, self::change(id));
+            assert!(changes.buf.capacity() <= 2 * READ_CHUNK);
+            id += 1;
+        }
+        assert_eq!(id as usize + events.changes.len(), 10_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt() -> Result<(), ReplicationError> {
+        let mut buffer = ChangeBuffer::new(Some(0), None);
+        buffer.push(1, change(1))?;
+        let events = buffer.into_events()?;
+        let spilled = events.spilled.as_ref().unwrap();
+        spilled.file.lock().unwrap().set_len(spilled.len - 1)?;
+
+        let mut changes = events.iter();
+        assert!(matches!(
+            changes.next(),
+            Some(Err(ReplicationError::Spill(_)))
+        ));
+        assert!(changes.next().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use super::{
+    connect, next_transaction, warn_unless_acknowledged, warn_unless_truncate_emitted, Acker,
+    Acknowledgements, Fanout, Lsn, ReplicationConfig, ReplicationError, Session, Transaction,
+};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::broadcast;
+
+/// a replication connection yielding committed transactions as a `Stream`
+///
+/// the server is only read from while the stream is polled so a slow consumer applies backpressure
+/// all the way to the walsender. standby status updates are sent while polling too, so a consumer
+/// must not stop polling for longer than the server's `wal_sender_timeout`.
+///
+/// positions are confirmed to the server once acknowledged with `ack`, and by every consumer
+/// registered with `acknowledgements`.
+pub struct ReplicationStream {
+    slot_name: String,
+    start_lsn: Lsn,
+    acknowledgements: Acknowledgements,
+    acker: Acker,
+    transactions: BoxStream<'static, Result<Transaction, ReplicationError>>,
+}
+
+impl std::fmt::Debug for ReplicationStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplicationStream")
+            .field("slot_name", &self.slot_name)
+            .field("start_lsn", &self.start_lsn)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReplicationStream {
+    /// connects, creates or reuses the slot and starts replication
+    pub async fn connect(config: ReplicationConfig) -> Result<Self, ReplicationError> {
+        warn_unless_truncate_emitted(&config);
+        let acknowledgements = Acknowledgements::new();
+        let session = connect(&config, &acknowledgements).await?;
+        Ok(Self::new(config, session, acknowledgements))
+    }
+
+    pub(crate) fn new(
+        config: ReplicationConfig,
+        session: Session,
+        acknowledgements: Acknowledgements,
+    ) -> Self {
+        let acker = acknowledgements.register();
+        let slot_name = session.slot.name.clone();
+        let start_lsn = session.slot.start_lsn;
+
+        let state = (session, config, acknowledgements.clone());
+        let transactions = futures::stream::try_unfold(
+            state,
+            |(mut session, config, acknowledgements)| async move {
+                let transaction =
+                    next_transaction(&mut session, &config, &acknowledgements).await?;
+                Ok(transaction
+                    .map(|transaction| (transaction, (session, config, acknowledgements))))
+            },
+        )
+        .boxed();
+
+        Self {
+            slot_name,
+            start_lsn,
+            acknowledgements,
+            acker,
+            transactions,
+        }
+    }
+
+    /// the name of the slot streamed from
+    pub fn slot_name(&self) -> &str {
+        &self.slot_name
+    }
+
+    /// the position streaming started from
+    pub fn start_lsn(&self) -> Lsn {
+        self.start_lsn
+    }
+
+    /// the consumers whose acknowledgements gate the positions confirmed to the server in addition
+    /// to this stream's own
+    pub fn acknowledgements(&self) -> &Acknowledgements {
+        &self.acknowledgements
+    }
+
+    /// acknowledges the transaction and every transaction yielded before it
+    pub fn ack(&self, transaction: &Transaction) {
+        self.acker.ack(transaction)
+    }
+
+    /// fans the transactions out to the subscribers of `tx` until the server ends the stream
+    ///
+    /// the stream stops acknowledging on its own, as a subscriber which lags or subscribes late
+    /// misses transactions, so only the consumers registered with `acknowledgements` gate the
+    /// positions confirmed to the server. nothing is confirmed until one is registered.
+    pub async fn broadcast(
+        self,
+        tx: broadcast::Sender<Arc<Transaction>>,
+    ) -> Result<(), ReplicationError> {
+        let Self {
+            acker,
+            acknowledgements,
+            mut transactions,
+            ..
+        } = self;
+        drop(acker);
+        warn_unless_acknowledged(&acknowledgements);
+
+        while let Some(transaction) = transactions.next().await {
+            tx.send(Arc::new(transaction?))
+                .map_err(|_| ReplicationError::ConsumerGone)?;
+        }
+        Ok(())
+    }
+
+    /// delivers the transactions to the subscribers of `fanout` until the server ends the stream
+    ///
+    /// unlike `broadcast` no subscriber ever misses a transaction: the stream waits for the slowest
+    /// subscriber before reading further from the server. like `broadcast` only the consumers
+    /// registered with `acknowledgements` gate the positions confirmed to the server, since a
+    /// subscriber receiving a transaction has not processed it yet.
+    pub async fn fanout(self, fanout: Fanout) -> Result<(), ReplicationError> {
+        let Self {
+            acker,
+            acknowledgements,
+            mut transactions,
+            ..
+        } = self;
+        drop(acker);
+        warn_unless_acknowledged(&acknowledgements);
+
+        while let Some(transaction) = transactions.next().await {
+            fanout.send(Arc::new(transaction?)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Stream for ReplicationStream {
+    type Item = Result<Transaction, ReplicationError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.transactions.poll_next_unpin(cx)
+    }
+}
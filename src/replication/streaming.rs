@@ -0,0 +1,179 @@
+use super::{spill::ChangeBuffer, Change, Events, Filter, ReplicationConfig, ReplicationError};
+use std::collections::HashMap;
+use tracing::{debug, trace};
+
+/// a transaction streamed by the server before it commits
+#[derive(Debug, Default)]
+struct StreamedTransaction {
+    changes: ChangeBuffer,
+    /// whether the filter dropped any of its changes
+    filtered: bool,
+}
+
+/// the in-progress transactions the server streams in blocks of changes before they commit
+///
+/// the blocks of several transactions may interleave so the changes of each are buffered, and
+/// spilled beyond the configured threshold, until its commit or abort arrives. they are only
+/// delivered once committed so consumers never see changes which roll back later.
+#[derive(Debug, Default)]
+pub(crate) struct StreamedTransactions {
+    transactions: HashMap<u32, StreamedTransaction>,
+    /// the top level transaction of the block being received
+    current: Option<u32>,
+}
+
+impl StreamedTransactions {
+    /// whether any streamed transaction has neither committed nor aborted yet
+    pub fn in_progress(&self) -> bool {
+        !self.transactions.is_empty()
+    }
+
+    /// starts a block of changes of the transaction `xid`
+    pub fn start(&mut self, xid: u32, config: &ReplicationConfig) -> Result<(), ReplicationError> {
+        if let Some(current) = self.current {
+            return Err(ReplicationError::Protocol(format!(
+                "STREAM START of transaction {} within the block of transaction {}",
+                xid, current
+            )));
+        }
+
+        self.current = Some(xid);
+        self.transactions
+            .entry(xid)
+            .or_insert_with(|| StreamedTransaction {
+                changes: ChangeBuffer::new(config.spill_threshold, config.spill_directory.clone()),
+                filtered: false,
+            });
+        Ok(())
+    }
+
+    /// ends the block of changes being received
+    pub fn stop(&mut self) {
+        self.current = None;
+    }
+
+    /// buffers a change made by the (sub)transaction `xid` within the current block
+    pub fn push(
+        &mut self,
+        xid: u32,
+        change: Change,
+        filter: &Filter,
+    ) -> Result<(), ReplicationError> {
+        let transaction = self
+            .current
+            .and_then(|current| self.transactions.get_mut(&current))
+            .ok_or_else(|| {
+                ReplicationError::Protocol("streamed change outside of a block".to_string())
+            })?;
+
+        match filter.apply(change) {
+            Some(change) => transaction.changes.push(xid, change),
+            None => {
+                transaction.filtered = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// discards the changes of the subtransaction `subxid` of `xid`, or the whole transaction if
+    /// they are the same
+    pub fn abort(&mut self, xid: u32, subxid: u32) {
+        if xid == subxid {
+            debug!("Discarding aborted streamed transaction {}", xid);
+            self.transactions.remove(&xid);
+        } else if let Some(transaction) = self.transactions.get_mut(&xid) {
+            trace!("Discarding aborted subtransaction {} of {}", subxid, xid);
+            transaction.changes.discard(subxid);
+        }
+    }
+
+    /// the changes of the committed transaction `xid` and whether the filter dropped any of them
+    pub fn commit(&mut self, xid: u32) -> Result<(Events, bool), ReplicationError> {
+        let transaction = self.transactions.remove(&xid).ok_or_else(|| {
+            ReplicationError::Protocol(format!("STREAM COMMIT of unknown transaction {}", xid))
+        })?;
+        Ok((transaction.changes.into_events()?, transaction.filtered))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::{
+        test_util::{column, row_change},
+        Op, Value,
+    };
+
+    fn change(table: &str, id: i32) -> Change {
+        row_change(table, Op::Insert, vec![column("id", Value::Int4(id))])
+    }
+
+    #[test]
+    fn test_interleaved_transactions() -> Result<(), ReplicationError> {
+        let config = ReplicationConfig::new();
+        let filter = Filter::new().exclude_table("public.audit");
+        let mut streamed = StreamedTransactions::default();
+
+        streamed.start(10, &config)?;
+        streamed.push(10, change("public.tenants", 1), &filter)?;
+        streamed.push(11, change("public.tenants", 2), &filter)?;
+        streamed.stop();
+
+        streamed.start(20, &config)?;
+        streamed.push(20, change("public.tenants", 3), &filter)?;
+        streamed.push(20, change("public.audit", 4), &filter)?;
+        streamed.stop();
+
+        streamed.start(10, &config)?;
+        streamed.push(12, change("public.tenants", 5), &filter)?;
+        streamed.push(10, change("public.tenants", 6), &filter)?;
+        streamed.stop();
+
+        // rolling back to a savepoint discards the changes of the subtransaction only
+        streamed.abort(10, 12);
+        let (events, filtered) = streamed.commit(10)?;
+        assert_eq!(
+            events.to_vec()?,
+            vec![
+                change("public.tenants", 1),
+                change("public.tenants", 2),
+                change("public.tenants", 6)
+            ]
+        );
+        assert!(!filtered);
+        assert!(streamed.in_progress());
+
+        streamed.abort(20, 20);
+        assert!(!streamed.in_progress());
+        assert!(matches!(
+            streamed.commit(20),
+            Err(ReplicationError::Protocol(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocks() -> Result<(), ReplicationError> {
+        let config = ReplicationConfig::new();
+        let filter = Filter::new().exclude_table("public.audit");
+        let mut streamed = StreamedTransactions::default();
+
+        assert!(matches!(
+            streamed.push(10, change("public.tenants", 1), &filter),
+            Err(ReplicationError::Protocol(_))
+        ));
+
+        streamed.start(10, &config)?;
+        assert!(matches!(
+            streamed.start(20, &config),
+            Err(ReplicationError::Protocol(_))
+        ));
+        streamed.push(10, change("public.audit", 1), &filter)?;
+        streamed.stop();
+
+        let (events, filtered) = streamed.commit(10)?;
+        assert!(events.is_empty());
+        assert!(filtered);
+        Ok(())
+    }
+}
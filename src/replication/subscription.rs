@@ -0,0 +1,546 @@
+use super::{
+    Acker, Change, Op, PreparedTransactions, ReplicationError, Transaction, TransactionReceiver,
+    Value,
+};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+type Condition = Arc<dyn Fn(&Change) -> bool + Send + Sync>;
+
+/// the conditions a change has to meet to be delivered to a `Subscription`
+///
+/// equality conditions on columns holding integers, text, uuids, bytes, booleans, dates or timestamps
+/// are indexed so a change is only evaluated against the subscriptions that could match it.
+#[derive(Clone)]
+pub struct Predicate {
+    table: String,
+    ops: Vec<Op>,
+    equals: Vec<(String, Value)>,
+    condition: Option<Condition>,
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Predicate")
+            .field("table", &self.table)
+            .field("ops", &self.ops)
+            .field("equals", &self.equals)
+            .field("condition", &self.condition.is_some())
+            .finish()
+    }
+}
+
+impl Predicate {
+    /// matches every change of the schema qualified `table`
+    pub fn table(table: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            ops: vec![],
+            equals: vec![],
+            condition: None,
+        }
+    }
+
+    /// only matches changes with one of `ops`
+    pub fn ops(mut self, ops: impl IntoIterator<Item = Op>) -> Self {
+        self.ops.extend(ops);
+        self
+    }
+
+    /// only matches changes where `column` equals `value`
+    ///
+    /// the value is read from the new row, or from the old row for deletes and unchanged TOASTed
+    /// values. integers of different widths compare equal.
+    pub fn eq(mut self, column: impl Into<String>, value: Value) -> Self {
+        self.equals.push((column.into(), value));
+        self
+    }
+
+    /// only matches changes for which `condition` holds. it is evaluated after the other conditions.
+    pub fn condition(
+        mut self,
+        condition: impl Fn(&Change) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.condition = Some(Arc::new(condition));
+        self
+    }
+
+    /// whether `change` meets every condition
+    pub fn matches(&self, change: &Change) -> bool {
+        change.table == self.table
+            && (self.ops.is_empty() || self.ops.contains(&change.op))
+            && self.equals.iter().all(|(column, value)| {
+                column_value(change, column).is_some_and(|actual| values_equal(actual, value))
+            })
+            && match &self.condition {
+                Some(condition) => condition(change),
+                None => true,
+            }
+    }
+
+    /// the first equality condition usable as an index key
+    fn index_key(&self) -> Option<(&str, IndexKey)> {
+        self.equals
+            .iter()
+            .find_map(|(column, value)| Some((column.as_str(), IndexKey::new(value)?)))
+    }
+}
+
+/// a change delivered to a `Subscription` along with the transaction it was committed in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub xid: u32,
+    pub commit_time: u64,
+    pub change: Change,
+}
+
+/// the hashable form of the values equality conditions are indexed by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum IndexKey {
+    Bool(bool),
+    Int(i64),
+    Text(String),
+    Uuid(Uuid),
+    Bytea(Bytes),
+    Date(NaiveDate),
+    Timestamp(NaiveDateTime),
+    Timestamptz(DateTime<Utc>),
+}
+
+impl IndexKey {
+    fn new(value: &Value) -> Option<Self> {
+        let key = match value {
+            Value::Bool(value) => IndexKey::Bool(*value),
+            Value::Int2(value) => IndexKey::Int(*value as i64),
+            Value::Int4(value) => IndexKey::Int(*value as i64),
+            Value::Int8(value) => IndexKey::Int(*value),
+            Value::Text(value) => IndexKey::Text(value.clone()),
+            Value::Uuid(value) => IndexKey::Uuid(*value),
+            Value::Bytea(value) => IndexKey::Bytea(value.clone()),
+            Value::Date(value) => IndexKey::Date(*value),
+            Value::Timestamp(value) => IndexKey::Timestamp(*value),
+            Value::Timestamptz(value) => IndexKey::Timestamptz(*value),
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+fn values_equal(actual: &Value, expected: &Value) -> bool {
+    match (IndexKey::new(actual), IndexKey::new(expected)) {
+        (Some(actual), Some(expected)) => actual == expected,
+        _ => actual == expected,
+    }
+}
+
+/// the value of `column` in the new row, falling back to the old row
+fn column_value<'a>(change: &'a Change, column: &str) -> Option<&'a Value> {
+    match change.new_value(column) {
+        None | Some(Value::UnchangedToast) => change.old_value(column),
+        value => value,
+    }
+}
+
+struct Entry {
+    predicate: Predicate,
+    sender: mpsc::Sender<Notification>,
+    /// the number of notifications dropped since the subscription last received
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    entries: HashMap<u64, Entry>,
+    /// subscriptions by the table, column and value of their indexed equality condition
+    by_key: HashMap<(String, String, IndexKey), HashSet<u64>>,
+    /// subscriptions of a table without an indexable equality condition
+    by_table: HashMap<String, HashSet<u64>>,
+    /// the number of subscriptions indexed by each column of a table
+    indexed_columns: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Registry {
+    fn insert(
+        &mut self,
+        predicate: Predicate,
+        sender: mpsc::Sender<Notification>,
+        dropped: Arc<AtomicU64>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        match predicate.index_key() {
+            Some((column, key)) => {
+                *self
+                    .indexed_columns
+                    .entry(predicate.table.clone())
+                    .or_default()
+                    .entry(column.to_string())
+                    .or_default() += 1;
+                self.by_key
+                    .entry((predicate.table.clone(), column.to_string(), key))
+                    .or_default()
+                    .insert(id);
+            }
+            None => {
+                self.by_table
+                    .entry(predicate.table.clone())
+                    .or_default()
+                    .insert(id);
+            }
+        }
+        self.entries.insert(
+            id,
+            Entry {
+                predicate,
+                sender,
+                dropped,
+            },
+        );
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        let predicate = match self.entries.remove(&id) {
+            Some(entry) => entry.predicate,
+            None => return,
+        };
+
+        match predicate.index_key() {
+            Some((column, key)) => {
+                let index = (predicate.table.clone(), column.to_string(), key);
+                if let Some(ids) = self.by_key.get_mut(&index) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        self.by_key.remove(&index);
+                    }
+                }
+                if let Some(columns) = self.indexed_columns.get_mut(&predicate.table) {
+                    if let Some(count) = columns.get_mut(column) {
+                        *count -= 1;
+                        if *count == 0 {
+                            columns.remove(column);
+                        }
+                    }
+                    if columns.is_empty() {
+                        self.indexed_columns.remove(&predicate.table);
+                    }
+                }
+            }
+            None => {
+                if let Some(ids) = self.by_table.get_mut(&predicate.table) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        self.by_table.remove(&predicate.table);
+                    }
+                }
+            }
+        }
+    }
+
+    /// the subscriptions which could match `change`
+    fn candidates(&self, change: &Change) -> HashSet<u64> {
+        let mut candidates = self
+            .by_table
+            .get(&change.table)
+            .cloned()
+            .unwrap_or_default();
+
+        for column in self
+            .indexed_columns
+            .get(&change.table)
+            .into_iter()
+            .flat_map(HashMap::keys)
+        {
+            let key = match column_value(change, column).and_then(IndexKey::new) {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(ids) = self
+                .by_key
+                .get(&(change.table.clone(), column.clone(), key))
+            {
+                candidates.extend(ids);
+            }
+        }
+        candidates
+    }
+}
+
+/// a registry of predicate subscriptions fed by the replication stream
+///
+/// every change of a transaction is delivered to each subscription whose predicate it matches, those
+/// of a prepared transaction once it is committed. clones share the same subscriptions.
+#[derive(Clone)]
+pub struct Subscriptions {
+    registry: Arc<Mutex<Registry>>,
+    capacity: usize,
+    prepared: PreparedTransactions,
+}
+
+impl fmt::Debug for Subscriptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscriptions")
+            .field(
+                "subscriptions",
+                &self.registry.lock().unwrap().entries.len(),
+            )
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl Subscriptions {
+    /// creates an empty registry whose subscriptions buffer up to `capacity` notifications
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            registry: Default::default(),
+            capacity,
+            prepared: PreparedTransactions::new(),
+        }
+    }
+
+    /// subscribes to the changes matching `predicate` until the subscription drops
+    pub fn subscribe(&self, predicate: Predicate) -> Subscription {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let id = self
+            .registry
+            .lock()
+            .unwrap()
+            .insert(predicate, sender, dropped.clone());
+        Subscription {
+            id,
+            receiver,
+            dropped,
+            registry: Arc::downgrade(&self.registry),
+        }
+    }
+
+    /// delivers the changes of `transaction` to the matching subscriptions
+    ///
+    /// notifications for a subscription whose buffer is full are dropped and the subscription fails
+    /// with `Lagged` when it next receives. fails if the spilled changes of the transaction cannot be
+    /// read back. reading them back blocks, so call this outside of async code as `run` does.
+    pub fn dispatch(&self, transaction: &Transaction) -> Result<(), ReplicationError> {
+        let transaction = match self.prepared.committed(transaction)? {
+            Some(transaction) => transaction,
+            None => return Ok(()),
+        };
+        for change in transaction.events.iter() {
+            let change = change?;
+            // only locked once the change is read so subscribing never waits for the disk
+            let registry = self.registry.lock().unwrap();
+            for id in registry.candidates(&change) {
+                let entry = &registry.entries[&id];
+                if !entry.predicate.matches(&change) {
+                    continue;
+                }
+
+                let notification = Notification {
+                    xid: transaction.xid,
+                    commit_time: transaction.commit_time,
+                    change: (*change).clone(),
+                };
+                if let Err(mpsc::error::TrySendError::Full(_)) = entry.sender.try_send(notification)
+                {
+                    warn!("Dropping notification for full subscription {}", id);
+                    entry.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// dispatches every transaction received from `rx` and acknowledges it with `acker`
+    ///
+    /// prepared transactions are only acknowledged once committed or rolled back. returns once the
+    /// replication stream closes or with an error if transactions were missed because the
+    /// dispatcher lagged behind a broadcast.
+    pub async fn run(
+        &self,
+        rx: impl Into<TransactionReceiver>,
+        acker: Acker,
+    ) -> Result<(), ReplicationError> {
+        let mut rx = rx.into();
+        while let Some(transaction) = rx.recv().await? {
+            // spilled changes are read back from disk while dispatching
+            let subscriptions = self.clone();
+            let dispatched = transaction.clone();
+            tokio::task::spawn_blocking(move || subscriptions.dispatch(&dispatched))
+                .await
+                .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))?;
+            self.prepared.ack(&acker, &transaction);
+        }
+        Ok(())
+    }
+}
+
+/// the receiving end of a predicate subscription. dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    receiver: mpsc::Receiver<Notification>,
+    dropped: Arc<AtomicU64>,
+    registry: Weak<Mutex<Registry>>,
+}
+
+impl Subscription {
+    /// the next matching change, or `None` once the registry is gone
+    ///
+    /// fails once with `Lagged` if notifications were dropped because the buffer was full, before
+    /// the notifications buffered since are received.
+    pub async fn recv(&mut self) -> Result<Option<Notification>, ReplicationError> {
+        self.lagged()?;
+        Ok(self.receiver.recv().await)
+    }
+
+    /// the next matching change if one is buffered, failing like `recv` if notifications were
+    /// dropped
+    pub fn try_recv(&mut self) -> Result<Option<Notification>, ReplicationError> {
+        self.lagged()?;
+        Ok(self.receiver.try_recv().ok())
+    }
+
+    fn lagged(&self) -> Result<(), ReplicationError> {
+        match self.dropped.swap(0, Ordering::Relaxed) {
+            0 => Ok(()),
+            dropped => Err(ReplicationError::Lagged(dropped)),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.lock().unwrap().remove(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::{
+        spill::ChangeBuffer,
+        test_util::{column, row_change, transaction},
+        Acknowledgements, Lsn,
+    };
+    use tokio::sync::broadcast;
+
+    fn change(op: Op, tenant_id: i64, name: &str) -> Change {
+        row_change(
+            "public.tenants",
+            op,
+            vec![
+                column("tenant_id", Value::Int8(tenant_id)),
+                column("name", Value::Text(name.to_string())),
+            ],
+        )
+    }
+
+    fn names(subscription: &mut Subscription) -> Vec<String> {
+        std::iter::from_fn(|| subscription.try_recv().unwrap())
+            .map(|notification| match notification.change.new_value("name") {
+                Some(Value::Text(name)) => name.clone(),
+                _ => format!("{:?}", notification.change.op),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let subscriptions = Subscriptions::new(10);
+        let mut updates = subscriptions.subscribe(
+            Predicate::table("public.tenants")
+                .eq("tenant_id", Value::Int4(1))
+                .ops([Op::Update]),
+        );
+        let mut all = subscriptions.subscribe(Predicate::table("public.tenants"));
+        let mut other = subscriptions.subscribe(
+            Predicate::table("public.tenants")
+                .eq("tenant_id", Value::Int8(2))
+                .condition(|change| change.op != Op::Insert),
+        );
+        let mut unindexed = subscriptions.subscribe(
+            Predicate::table("public.tenants").eq("name", Value::Json(serde_json::json!("b"))),
+        );
+
+        subscriptions
+            .dispatch(&transaction(
+                1,
+                vec![
+                    change(Op::Insert, 1, "a"),
+                    change(Op::Update, 1, "b"),
+                    change(Op::Insert, 2, "c"),
+                    change(Op::Delete, 2, "d"),
+                ],
+            ))
+            .unwrap();
+
+        assert_eq!(names(&mut updates), vec!["b"]);
+        assert_eq!(names(&mut all), vec!["a", "b", "c", "Delete"]);
+        assert_eq!(names(&mut other), vec!["Delete"]);
+        assert!(names(&mut unindexed).is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let subscriptions = Subscriptions::new(1);
+        let subscription = subscriptions
+            .subscribe(Predicate::table("public.tenants").eq("tenant_id", Value::Int8(1)));
+        let mut full = subscriptions.subscribe(Predicate::table("public.tenants"));
+
+        subscriptions
+            .dispatch(&transaction(
+                1,
+                vec![change(Op::Insert, 1, "a"), change(Op::Insert, 1, "b")],
+            ))
+            .unwrap();
+        // notifications beyond the capacity are dropped and reported
+        assert!(matches!(full.try_recv(), Err(ReplicationError::Lagged(1))));
+        assert_eq!(names(&mut full), vec!["a"]);
+
+        drop(subscription);
+        let registry = subscriptions.registry.lock().unwrap();
+        assert_eq!(registry.entries.len(), 1);
+        assert!(registry.by_key.is_empty());
+        assert!(registry.indexed_columns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_spilled() -> Result<(), ReplicationError> {
+        let subscriptions = Subscriptions::new(10);
+        let mut all = subscriptions.subscribe(Predicate::table("public.tenants"));
+        let acknowledgements = Acknowledgements::new();
+        let acker = acknowledgements.register();
+
+        let mut changes = ChangeBuffer::new(Some(1), None);
+        changes.push(1, change(Op::Insert, 1, "a"))?;
+        changes.push(1, change(Op::Insert, 2, "b"))?;
+        let spilled = Transaction {
+            events: changes.into_events()?,
+            ..transaction(1, vec![])
+        };
+        let (tx, rx) = broadcast::channel(1);
+        tx.send(Arc::new(spilled)).unwrap();
+        drop(tx);
+
+        subscriptions.run(rx, acker.clone()).await?;
+        assert_eq!(names(&mut all), vec!["a", "b"]);
+        assert_eq!(acknowledgements.acknowledged(), Some(Lsn::new(1)));
+        Ok(())
+    }
+}
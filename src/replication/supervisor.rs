@@ -0,0 +1,95 @@
+use super::{
+    connect, stream_changes, warn_unless_acknowledged, warn_unless_truncate_emitted,
+    Acknowledgements, Lsn, ReplicationConfig, ReplicationError, Transaction,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{info, warn};
+
+/// changes in the state of a supervised replication stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    /// the first connection was established and streaming started at `start_lsn`
+    Connected { slot_name: String, start_lsn: Lsn },
+    /// the connection was lost and a reconnect is attempted after `retry_in`
+    Disconnected { reason: String, retry_in: Duration },
+    /// streaming resumed after a reconnect at `lsn`
+    Resumed { slot_name: String, lsn: Lsn },
+}
+
+/// streams changes like `start_streaming_changes` but reconnects with exponential backoff whenever the
+/// connection fails or the server ends the stream.
+///
+/// every reconnect issues `START_REPLICATION` from the minimum position acknowledged by `acknowledgements`
+/// so transactions which were delivered but not acknowledged are redelivered. this requires a persistent
+/// slot as a temporary slot is dropped with its connection. `ready` fires after the first connection and
+/// non-transient errors are returned to the caller.
+pub async fn supervise_streaming_changes(
+    config: ReplicationConfig,
+    ready: oneshot::Sender<()>,
+    tx: broadcast::Sender<Arc<Transaction>>,
+    acknowledgements: Acknowledgements,
+    lifecycle: broadcast::Sender<LifecycleEvent>,
+) -> Result<(), ReplicationError> {
+    if config.temporary_slot {
+        return Err(ReplicationError::Config(
+            "supervised replication requires a persistent slot".to_string(),
+        ));
+    }
+
+    warn_unless_truncate_emitted(&config);
+    warn_unless_acknowledged(&acknowledgements);
+    let mut ready = Some(ready);
+    let mut backoff = config.initial_backoff;
+    loop {
+        let mut session_config = config.clone();
+        if ready.is_none() {
+            if let Some(lsn) = acknowledgements.acknowledged() {
+                session_config.start_lsn = Some(lsn);
+            }
+        }
+
+        let result = match connect(&session_config, &acknowledgements).await {
+            Ok(mut session) => {
+                backoff = config.initial_backoff;
+
+                let slot_name = session.slot.name.clone();
+                let event = match ready.take() {
+                    Some(ready) => {
+                        ready.send(()).map_err(|_| ReplicationError::ConsumerGone)?;
+                        LifecycleEvent::Connected {
+                            slot_name,
+                            start_lsn: session.slot.start_lsn,
+                        }
+                    }
+                    None => LifecycleEvent::Resumed {
+                        slot_name,
+                        lsn: session.slot.start_lsn,
+                    },
+                };
+                info!("{:?}", event);
+                // lifecycle subscribers are optional
+                let _ = lifecycle.send(event);
+
+                stream_changes(&mut session, &session_config, &tx, &acknowledgements).await
+            }
+            Err(err) => Err(err),
+        };
+
+        let reason = match result {
+            Ok(()) => "replication stream ended".to_string(),
+            Err(err) if err.is_transient() => err.to_string(),
+            Err(err) => return Err(err),
+        };
+
+        let event = LifecycleEvent::Disconnected {
+            reason,
+            retry_in: backoff,
+        };
+        warn!("{:?}", event);
+        let _ = lifecycle.send(event);
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
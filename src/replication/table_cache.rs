@@ -0,0 +1,510 @@
+use super::{
+    Acker, Change, FromChange, Lsn, Op, PreparedTransactions, ReplicationConfig, ReplicationError,
+    Snapshot, Transaction, TransactionReceiver,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::broadcast;
+use tracing::{debug, trace, warn};
+
+/// the rows of a table changed by a transaction applied to a `TableCache`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableCacheUpdate<K> {
+    pub xid: u32,
+    pub commit_lsn: Lsn,
+    /// the key and operation of every change in the order they were applied
+    pub changes: Vec<(Op, K)>,
+}
+
+/// an in-memory copy of a table kept in sync by the replication stream
+///
+/// the changes of a transaction are applied atomically so readers never observe a partially applied
+/// transaction. the changes of a prepared transaction are only applied once it is committed. clones
+/// share the same rows.
+pub struct TableCache<K, V> {
+    table: Arc<str>,
+    rows: Arc<RwLock<HashMap<K, V>>>,
+    updates: broadcast::Sender<TableCacheUpdate<K>>,
+    prepared: PreparedTransactions,
+}
+
+impl<K, V> Clone for TableCache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            table: self.table.clone(),
+            rows: self.rows.clone(),
+            updates: self.updates.clone(),
+            prepared: self.prepared.clone(),
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for TableCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TableCache")
+            .field("table", &self.table)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V> TableCache<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone + FromChange<Key = K>,
+{
+    /// creates an empty cache of the schema qualified `table`, e.g. `public.tenants`
+    pub fn new(table: impl Into<String>) -> Self {
+        let (updates, _) = broadcast::channel(100);
+        Self {
+            table: table.into().into(),
+            rows: Default::default(),
+            updates,
+            prepared: PreparedTransactions::new(),
+        }
+    }
+
+    /// the schema qualified name of the cached table
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// the row with `key`
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.rows.read().unwrap().get(key).cloned()
+    }
+
+    /// a snapshot of every row as of the last applied transaction
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> {
+        self.rows
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, row)| (key.clone(), row.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// subscribes to the updates of every transaction applied from now on which changed the table
+    pub fn subscribe(&self) -> broadcast::Receiver<TableCacheUpdate<K>> {
+        self.updates.subscribe()
+    }
+
+    /// replaces the rows with those of the table in `snapshot`
+    ///
+    /// used to bootstrap the cache before streaming starts, see
+    /// `start_streaming_changes_from_snapshot`. subscribers are not notified.
+    pub async fn load(&self, snapshot: &Snapshot) -> Result<(), ReplicationError> {
+        let rows = snapshot
+            .changes(&self.table)
+            .await?
+            .iter()
+            .map(|change| {
+                let row = V::from_insert(change)?;
+                Ok((row.key(), row))
+            })
+            .collect::<Result<HashMap<_, _>, ReplicationError>>()?;
+
+        debug!("loaded {} rows of {} from snapshot", rows.len(), self.table);
+        *self.rows.write().unwrap() = rows;
+        Ok(())
+    }
+
+    /// applies the changes of `transaction` to the table
+    ///
+    /// if any change cannot be mapped onto a row the cache is left untouched and the error returned.
+    pub fn apply(&self, transaction: &Transaction) -> Result<(), ReplicationError> {
+        self.apply_changes(transaction, false)
+    }
+
+    /// applies the changes of `transaction`, skipping updates of missing rows if `replaying`
+    fn apply_changes(
+        &self,
+        transaction: &Transaction,
+        replaying: bool,
+    ) -> Result<(), ReplicationError> {
+        let transaction = match self.prepared.committed(transaction)? {
+            Some(transaction) => transaction,
+            None => return Ok(()),
+        };
+
+        // stage the changed rows so a failing change leaves the cache untouched
+        let mut staged: HashMap<K, Option<V>> = HashMap::new();
+        let mut applied = Vec::new();
+        let mut changed = false;
+        {
+            let rows = self.rows.read().unwrap();
+            for change in transaction.events.iter() {
+                let change = change?;
+                if change.table != *self.table {
+                    continue;
+                }
+                changed = true;
+                self.stage(&rows, &mut staged, &mut applied, &change, replaying)?;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        let mut rows = self.rows.write().unwrap();
+        for (key, row) in staged {
+            match row {
+                Some(row) => rows.insert(key, row),
+                None => rows.remove(&key),
+            };
+        }
+        drop(rows);
+
+        trace!("applied {} changes to {}", applied.len(), self.table);
+        // update subscribers are optional
+        let _ = self.updates.send(TableCacheUpdate {
+            xid: transaction.xid,
+            commit_lsn: transaction.commit_lsn,
+            changes: applied,
+        });
+        Ok(())
+    }
+
+    /// stages the rows changed by `change` and records their keys in `applied`
+    fn stage(
+        &self,
+        rows: &HashMap<K, V>,
+        staged: &mut HashMap<K, Option<V>>,
+        applied: &mut Vec<(Op, K)>,
+        change: &Change,
+        replaying: bool,
+    ) -> Result<(), ReplicationError> {
+        match change.op {
+            Op::Insert => {
+                let row = V::from_insert(change)?;
+                let key = row.key();
+                staged.insert(key.clone(), Some(row));
+                applied.push((Op::Insert, key));
+            }
+            Op::Update => {
+                let key = V::key_from_change(change)?;
+                let row = match staged.get(&key) {
+                    Some(row) => row.clone(),
+                    None => rows.get(&key).cloned(),
+                };
+                let mut row = match row {
+                    Some(row) => row,
+                    // the row was deleted by a later transaction already loaded
+                    None if replaying => return Ok(()),
+                    None => {
+                        return Err(ReplicationError::Mapping(format!(
+                            "update of a row missing from {}",
+                            self.table
+                        )))
+                    }
+                };
+                row.apply_update(change)?;
+
+                // the update may have changed the key
+                let new_key = row.key();
+                if new_key != key {
+                    staged.insert(key, None);
+                }
+                staged.insert(new_key.clone(), Some(row));
+                applied.push((Op::Update, new_key));
+            }
+            Op::Delete => {
+                let key = V::key_from_delete(change)?;
+                staged.insert(key.clone(), None);
+                applied.push((Op::Delete, key));
+            }
+            Op::Truncate => {
+                // every row present at this point, including those staged earlier in the transaction
+                let keys = rows
+                    .keys()
+                    .chain(staged.keys())
+                    .filter(|key| match staged.get(*key) {
+                        Some(row) => row.is_some(),
+                        None => true,
+                    })
+                    .cloned()
+                    .collect::<HashSet<_>>();
+                for key in keys {
+                    staged.insert(key.clone(), None);
+                    applied.push((Op::Truncate, key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// applies every transaction received from `rx` and acknowledges it with `acker`
+    ///
+    /// prepared transactions are only acknowledged once committed or rolled back. returns once the
+    /// replication stream closes or with an error if a transaction cannot be applied or was missed
+    /// because the cache lagged behind a broadcast.
+    pub async fn run(
+        &self,
+        rx: impl Into<TransactionReceiver>,
+        acker: Acker,
+    ) -> Result<(), ReplicationError> {
+        let mut rx = rx.into();
+        while let Some(transaction) = rx.recv().await? {
+            self.apply(&transaction)?;
+            self.prepared.ack(&acker, &transaction);
+        }
+        Ok(())
+    }
+
+    /// like `run` but reloads the table from a snapshot of the current data whenever the cache
+    /// lagged behind a broadcast and missed transactions, or missed the prepare of a transaction
+    /// committed with `CommitPrepared`
+    ///
+    /// the transactions still buffered when the snapshot is taken are replayed on top of it. updates
+    /// of rows missing from the snapshot are skipped while replaying transactions which may already
+    /// be part of it, i.e. committed before its `consistent_point`.
+    pub async fn run_with_resync(
+        &self,
+        rx: impl Into<TransactionReceiver>,
+        acker: Acker,
+        config: &ReplicationConfig,
+    ) -> Result<(), ReplicationError> {
+        let mut rx = rx.into();
+        let mut replay_until = Lsn::INVALID;
+        loop {
+            let transaction = match rx.recv().await {
+                Ok(Some(transaction)) => transaction,
+                Ok(None) => return Ok(()),
+                Err(ReplicationError::Lagged(skipped)) => {
+                    warn!(
+                        "{} lagged behind by {} transactions, resynchronising",
+                        self.table, skipped
+                    );
+                    replay_until = self.resync(config).await?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            match self.apply_changes(&transaction, transaction.commit_lsn <= replay_until) {
+                Ok(()) => {}
+                // the snapshot taken now already contains the committed changes
+                Err(ReplicationError::UnknownPrepared(gid)) => {
+                    warn!(
+                        "{} missed prepared transaction {:?}, resynchronising",
+                        self.table, gid
+                    );
+                    replay_until = self.resync(config).await?;
+                }
+                Err(err) => return Err(err),
+            }
+            self.prepared.ack(&acker, &transaction);
+        }
+    }
+
+    /// reloads the table from a snapshot of the current data, returning its `consistent_point`
+    async fn resync(&self, config: &ReplicationConfig) -> Result<Lsn, ReplicationError> {
+        let snapshot = Snapshot::current(config).await?;
+        self.load(&snapshot).await?;
+        Ok(snapshot.consistent_point)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::{
+        test_util::{column, row_change, transaction},
+        TransactionKind, Truncate, Value,
+    };
+
+    #[derive(Debug, Clone, PartialEq, FromChange)]
+    struct Tenant {
+        #[key]
+        id: i32,
+        name: String,
+    }
+
+    fn change(op: Op, table: &str, id: i32, name: Option<&str>) -> Change {
+        let mut columns = vec![column("id", Value::Int4(id))];
+        if let Some(name) = name {
+            columns.push(column("name", Value::Text(name.to_string())));
+        }
+        row_change(table, op, columns)
+    }
+
+    fn tenant(id: i32, name: &str) -> Tenant {
+        Tenant {
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), ReplicationError> {
+        let cache = TableCache::<i32, Tenant>::new("public.tenants");
+        let mut updates = cache.subscribe();
+
+        cache.apply(&transaction(
+            1,
+            vec![
+                change(Op::Insert, "public.tenants", 1, Some("one")),
+                change(Op::Insert, "public.tenants", 2, Some("two")),
+                change(Op::Insert, "public.other", 3, Some("three")),
+                change(Op::Update, "public.tenants", 2, Some("deux")),
+            ],
+        ))?;
+        assert_eq!(cache.get(&1), Some(tenant(1, "one")));
+        assert_eq!(cache.get(&2), Some(tenant(2, "deux")));
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(
+            updates.try_recv().unwrap(),
+            TableCacheUpdate {
+                xid: 1,
+                commit_lsn: Lsn::new(1),
+                changes: vec![(Op::Insert, 1), (Op::Insert, 2), (Op::Update, 2)],
+            }
+        );
+
+        // transactions which do not touch the table are not notified
+        cache.apply(&transaction(
+            2,
+            vec![change(Op::Delete, "public.other", 3, None)],
+        ))?;
+        assert!(updates.try_recv().is_err());
+
+        cache.apply(&transaction(
+            3,
+            vec![change(Op::Delete, "public.tenants", 1, None)],
+        ))?;
+        let mut rows = cache.iter().collect::<Vec<_>>();
+        rows.sort_by_key(|(key, _)| *key);
+        assert_eq!(rows, vec![(2, tenant(2, "deux"))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_is_atomic() {
+        let cache = TableCache::<i32, Tenant>::new("public.tenants");
+
+        let result = cache.apply(&transaction(
+            1,
+            vec![
+                change(Op::Insert, "public.tenants", 1, Some("one")),
+                change(Op::Update, "public.tenants", 2, Some("two")),
+            ],
+        ));
+        assert!(matches!(result, Err(ReplicationError::Mapping(_))));
+        assert_eq!(cache.get(&1), None);
+
+        // while replaying after a resync the missing row may have been deleted later
+        let replayed = transaction(
+            1,
+            vec![
+                change(Op::Insert, "public.tenants", 1, Some("one")),
+                change(Op::Update, "public.tenants", 2, Some("two")),
+            ],
+        );
+        cache.apply_changes(&replayed, true).unwrap();
+        assert_eq!(cache.get(&1), Some(tenant(1, "one")));
+        assert_eq!(cache.get(&2), None);
+    }
+
+    #[test]
+    fn test_apply_truncate() -> Result<(), ReplicationError> {
+        let cache = TableCache::<i32, Tenant>::new("public.tenants");
+        let mut updates = cache.subscribe();
+        let truncate = Change {
+            op: Op::Truncate,
+            new: vec![],
+            truncate: Some(Truncate {
+                tables: vec!["public.tenants".to_string()],
+                ..Default::default()
+            }),
+            ..change(Op::Truncate, "public.tenants", 0, None)
+        };
+
+        cache.apply(&transaction(
+            1,
+            vec![
+                change(Op::Insert, "public.tenants", 1, Some("one")),
+                change(Op::Insert, "public.tenants", 2, Some("two")),
+            ],
+        ))?;
+        updates.try_recv().unwrap();
+
+        cache.apply(&transaction(
+            2,
+            vec![
+                change(Op::Insert, "public.tenants", 3, Some("three")),
+                change(Op::Delete, "public.tenants", 2, None),
+                truncate,
+                change(Op::Insert, "public.tenants", 4, Some("four")),
+            ],
+        ))?;
+        let rows = cache.iter().collect::<Vec<_>>();
+        assert_eq!(rows, vec![(4, tenant(4, "four"))]);
+
+        // every row present when truncated is notified, the deleted one only once
+        let (mut truncated, changes): (Vec<_>, Vec<_>) = updates
+            .try_recv()
+            .unwrap()
+            .changes
+            .into_iter()
+            .partition(|(op, _)| *op == Op::Truncate);
+        truncated.sort_by_key(|(_, key)| *key);
+        assert_eq!(truncated, vec![(Op::Truncate, 1), (Op::Truncate, 3)]);
+        assert_eq!(
+            changes,
+            vec![(Op::Insert, 3), (Op::Delete, 2), (Op::Insert, 4)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_prepared() -> Result<(), ReplicationError> {
+        let cache = TableCache::<i32, Tenant>::new("public.tenants");
+        let mut updates = cache.subscribe();
+        let ended = |xid: u32, kind: TransactionKind| Transaction {
+            kind,
+            ..transaction(xid, vec![])
+        };
+
+        // prepared changes are only applied once committed
+        cache.apply(&Transaction {
+            kind: TransactionKind::Prepare {
+                gid: "one".to_string(),
+            },
+            ..transaction(
+                1,
+                vec![change(Op::Insert, "public.tenants", 1, Some("one"))],
+            )
+        })?;
+        assert_eq!(cache.get(&1), None);
+
+        cache.apply(&ended(
+            2,
+            TransactionKind::CommitPrepared {
+                gid: "one".to_string(),
+            },
+        ))?;
+        assert_eq!(cache.get(&1), Some(tenant(1, "one")));
+        assert_eq!(updates.try_recv().unwrap().commit_lsn, Lsn::new(2));
+
+        cache.apply(&Transaction {
+            kind: TransactionKind::Prepare {
+                gid: "two".to_string(),
+            },
+            ..transaction(3, vec![change(Op::Delete, "public.tenants", 1, None)])
+        })?;
+        cache.apply(&ended(
+            4,
+            TransactionKind::RollbackPrepared {
+                gid: "two".to_string(),
+            },
+        ))?;
+        assert_eq!(cache.get(&1), Some(tenant(1, "one")));
+        Ok(())
+    }
+}
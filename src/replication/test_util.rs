@@ -0,0 +1,48 @@
+use super::{Change, Column, Lsn, Op, Transaction, TransactionKind, Value, XLogDataHeader};
+use std::time::SystemTime;
+
+/// a column without a type oid
+pub(crate) fn column(name: &str, value: Value) -> Column {
+    Column {
+        name: name.to_string(),
+        type_oid: None,
+        value,
+    }
+}
+
+/// a change of `table` with the given old and new rows
+pub(crate) fn change(table: &str, op: Op, old: Vec<Column>, new: Vec<Column>) -> Change {
+    Change {
+        table: table.to_string(),
+        op,
+        old,
+        new,
+        lsn: Lsn::INVALID,
+        truncate: None,
+    }
+}
+
+/// a change of `table` whose `columns` are the old row of a delete and the new row otherwise
+pub(crate) fn row_change(table: &str, op: Op, columns: Vec<Column>) -> Change {
+    match op {
+        Op::Delete => change(table, op, columns, vec![]),
+        _ => change(table, op, vec![], columns),
+    }
+}
+
+/// a committed transaction whose xid and commit lsn are both `xid`
+pub(crate) fn transaction(xid: u32, events: Vec<Change>) -> Transaction {
+    let commit_lsn = Lsn::new(xid as u64);
+    Transaction {
+        xid,
+        commit_time: 0,
+        commit_lsn,
+        header: XLogDataHeader {
+            wal_start: commit_lsn,
+            wal_end: commit_lsn,
+            send_time: SystemTime::UNIX_EPOCH,
+        },
+        events: events.into(),
+        kind: TransactionKind::Commit,
+    }
+}
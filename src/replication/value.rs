@@ -0,0 +1,479 @@
+use super::{
+    decoderbufs::{datum_message::Datum, DatumMessage},
+    ReplicationError,
+};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+/// oids of the builtin types values are decoded for
+///
+/// see `pg_type.dat` in the server sources for the full list
+pub(crate) mod oid {
+    pub const BOOL: u32 = 16;
+    pub const BYTEA: u32 = 17;
+    pub const INT8: u32 = 20;
+    pub const INT2: u32 = 21;
+    pub const INT4: u32 = 23;
+    pub const TEXT: u32 = 25;
+    pub const JSON: u32 = 114;
+    pub const JSON_ARRAY: u32 = 199;
+    pub const POINT: u32 = 600;
+    pub const FLOAT4: u32 = 700;
+    pub const FLOAT8: u32 = 701;
+    pub const BOOL_ARRAY: u32 = 1000;
+    pub const BYTEA_ARRAY: u32 = 1001;
+    pub const INT2_ARRAY: u32 = 1005;
+    pub const INT4_ARRAY: u32 = 1007;
+    pub const TEXT_ARRAY: u32 = 1009;
+    pub const BPCHAR_ARRAY: u32 = 1014;
+    pub const VARCHAR_ARRAY: u32 = 1015;
+    pub const INT8_ARRAY: u32 = 1016;
+    pub const POINT_ARRAY: u32 = 1017;
+    pub const FLOAT4_ARRAY: u32 = 1021;
+    pub const FLOAT8_ARRAY: u32 = 1022;
+    pub const BPCHAR: u32 = 1042;
+    pub const VARCHAR: u32 = 1043;
+    pub const DATE: u32 = 1082;
+    pub const TIMESTAMP: u32 = 1114;
+    pub const TIMESTAMP_ARRAY: u32 = 1115;
+    pub const DATE_ARRAY: u32 = 1182;
+    pub const TIMESTAMPTZ: u32 = 1184;
+    pub const TIMESTAMPTZ_ARRAY: u32 = 1185;
+    pub const NUMERIC_ARRAY: u32 = 1231;
+    pub const NUMERIC: u32 = 1700;
+    pub const UUID: u32 = 2950;
+    pub const UUID_ARRAY: u32 = 2951;
+    pub const JSONB: u32 = 3802;
+    pub const JSONB_ARRAY: u32 = 3807;
+
+    /// the element type of the builtin array types
+    pub fn element(type_oid: u32) -> Option<u32> {
+        let element = match type_oid {
+            JSON_ARRAY => JSON,
+            BOOL_ARRAY => BOOL,
+            BYTEA_ARRAY => BYTEA,
+            INT2_ARRAY => INT2,
+            INT4_ARRAY => INT4,
+            TEXT_ARRAY => TEXT,
+            BPCHAR_ARRAY => BPCHAR,
+            VARCHAR_ARRAY => VARCHAR,
+            INT8_ARRAY => INT8,
+            POINT_ARRAY => POINT,
+            FLOAT4_ARRAY => FLOAT4,
+            FLOAT8_ARRAY => FLOAT8,
+            TIMESTAMP_ARRAY => TIMESTAMP,
+            DATE_ARRAY => DATE,
+            TIMESTAMPTZ_ARRAY => TIMESTAMPTZ,
+            NUMERIC_ARRAY => NUMERIC,
+            UUID_ARRAY => UUID,
+            JSONB_ARRAY => JSONB,
+            _ => return None,
+        };
+        Some(element)
+    }
+}
+
+/// the number of days from 0001-01-01 to 1970-01-01
+static DAYS_FROM_CE_TO_UNIX_EPOCH: i32 = 719_163;
+
+/// a typed column value
+///
+/// values are decoded according to the oid of the column's type. types without a dedicated
+/// variant keep the textual form the server prints them in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    /// a TOASTed value which was not modified and therefore not sent
+    UnchangedToast,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Float(f64),
+    /// an arbitrary precision number in its exact textual form, e.g. `1.50` or `NaN`
+    Numeric(String),
+    Text(String),
+    Uuid(Uuid),
+    Bytea(Bytes),
+    /// a `timestamp` (without time zone). `infinity` maps to `NaiveDateTime::MAX`.
+    Timestamp(NaiveDateTime),
+    /// a `timestamptz`. `infinity` maps to `DateTime::<Utc>::MAX_UTC`.
+    Timestamptz(DateTime<Utc>),
+    /// a `date`. `infinity` maps to `NaiveDate::MAX`.
+    Date(NaiveDate),
+    /// a `json` or `jsonb` document
+    Json(serde_json::Value),
+    Point {
+        x: f64,
+        y: f64,
+    },
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// decodes a value from the textual form the server prints it in, e.g. in `pgoutput` tuples
+    pub fn from_text(type_oid: Option<u32>, text: &str) -> Result<Value, ReplicationError> {
+        let type_oid = match type_oid {
+            Some(type_oid) => type_oid,
+            None => return Ok(Value::Text(text.to_string())),
+        };
+        let invalid = || {
+            ReplicationError::Plugin(format!(
+                "invalid value {:?} for type oid {}",
+                text, type_oid
+            ))
+        };
+
+        let value = match type_oid {
+            oid::BOOL => match text {
+                "t" | "true" => Value::Bool(true),
+                "f" | "false" => Value::Bool(false),
+                _ => return Err(invalid()),
+            },
+            oid::INT2 => Value::Int2(text.parse().map_err(|_| invalid())?),
+            oid::INT4 => Value::Int4(text.parse().map_err(|_| invalid())?),
+            oid::INT8 => Value::Int8(text.parse().map_err(|_| invalid())?),
+            oid::FLOAT4 | oid::FLOAT8 => Value::Float(text.parse().map_err(|_| invalid())?),
+            oid::NUMERIC => Value::Numeric(text.to_string()),
+            oid::UUID => Value::Uuid(Uuid::parse_str(text).map_err(|_| invalid())?),
+            oid::BYTEA => Value::Bytea(parse_bytea(text).ok_or_else(invalid)?),
+            oid::TIMESTAMP => Value::Timestamp(match text {
+                "infinity" => NaiveDateTime::MAX,
+                "-infinity" => NaiveDateTime::MIN,
+                _ => NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+                    .map_err(|_| invalid())?,
+            }),
+            oid::TIMESTAMPTZ => Value::Timestamptz(match text {
+                "infinity" => DateTime::<Utc>::MAX_UTC,
+                "-infinity" => DateTime::<Utc>::MIN_UTC,
+                _ => DateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f%#z")
+                    .map_err(|_| invalid())?
+                    .with_timezone(&Utc),
+            }),
+            oid::DATE => Value::Date(match text {
+                "infinity" => NaiveDate::MAX,
+                "-infinity" => NaiveDate::MIN,
+                _ => NaiveDate::parse_from_str(text, "%Y-%m-%d").map_err(|_| invalid())?,
+            }),
+            oid::JSON | oid::JSONB => {
+                Value::Json(serde_json::from_str(text).map_err(|_| invalid())?)
+            }
+            oid::POINT => {
+                let (x, y) = text
+                    .strip_prefix('(')
+                    .and_then(|point| point.strip_suffix(')'))
+                    .and_then(|point| point.split_once(','))
+                    .ok_or_else(invalid)?;
+                Value::Point {
+                    x: x.parse().map_err(|_| invalid())?,
+                    y: y.parse().map_err(|_| invalid())?,
+                }
+            }
+            type_oid => match oid::element(type_oid) {
+                Some(element) => {
+                    let mut parser = ArrayParser { rest: text };
+                    let array = parser.array(element).ok_or_else(invalid)?;
+                    if !parser.rest.is_empty() {
+                        return Err(invalid());
+                    }
+                    array
+                }
+                None => Value::Text(text.to_string()),
+            },
+        };
+        Ok(value)
+    }
+}
+
+impl TryFrom<DatumMessage> for Value {
+    type Error = ReplicationError;
+
+    /// decodes a value sent by `decoderbufs` which already converts most builtin types to native
+    /// ones. dates and timestamps are sent as days and microseconds since the unix epoch.
+    fn try_from(datum: DatumMessage) -> Result<Self, Self::Error> {
+        let type_oid = datum.column_type.map(|type_oid| type_oid as u32);
+        let column_name = datum.column_name().to_string();
+        let out_of_range = |value: &dyn std::fmt::Debug| {
+            ReplicationError::Plugin(format!(
+                "value {:?} out of range for column {}",
+                value, column_name
+            ))
+        };
+
+        let value = match datum.datum {
+            None => Value::Null,
+            Some(Datum::DatumMissing(_)) => Value::UnchangedToast,
+            Some(Datum::DatumBool(value)) => Value::Bool(value),
+            Some(Datum::DatumInt32(value)) => match type_oid {
+                Some(oid::INT2) => Value::Int2(value.try_into().map_err(|_| out_of_range(&value))?),
+                Some(oid::DATE) => Value::Date(
+                    NaiveDate::from_num_days_from_ce_opt(
+                        value
+                            .checked_add(DAYS_FROM_CE_TO_UNIX_EPOCH)
+                            .ok_or_else(|| out_of_range(&value))?,
+                    )
+                    .ok_or_else(|| out_of_range(&value))?,
+                ),
+                _ => Value::Int4(value),
+            },
+            Some(Datum::DatumInt64(value)) => match type_oid {
+                Some(oid::TIMESTAMP) => Value::Timestamp(
+                    DateTime::from_timestamp_micros(value)
+                        .ok_or_else(|| out_of_range(&value))?
+                        .naive_utc(),
+                ),
+                Some(oid::TIMESTAMPTZ) => Value::Timestamptz(
+                    DateTime::from_timestamp_micros(value).ok_or_else(|| out_of_range(&value))?,
+                ),
+                _ => Value::Int8(value),
+            },
+            Some(Datum::DatumFloat(value)) => Value::Float(value as f64),
+            Some(Datum::DatumDouble(value)) => match type_oid {
+                Some(oid::NUMERIC) => Value::Numeric(value.to_string()),
+                _ => Value::Float(value),
+            },
+            Some(Datum::DatumString(value)) => Value::from_text(type_oid, &value)?,
+            Some(Datum::DatumBytes(value)) => Value::Bytea(value.into()),
+            Some(Datum::DatumPoint(point)) => Value::Point {
+                x: point.x,
+                y: point.y,
+            },
+        };
+        Ok(value)
+    }
+}
+
+/// parses the hex format of `bytea` such as `\x0aff`
+fn parse_bytea(text: &str) -> Option<Bytes> {
+    let hex = text.strip_prefix("\\x")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .map(Bytes::from)
+}
+
+/// parses array literals such as `{1,2}`, `{"a b",NULL}` or `{{1,2},{3,4}}`
+struct ArrayParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> ArrayParser<'a> {
+    fn array(&mut self, element: u32) -> Option<Value> {
+        // arrays with non-default lower bounds are prefixed with their dimensions, e.g. `[0:1]={1,2}`
+        if self.rest.starts_with('[') {
+            self.rest = &self.rest[self.rest.find('=')? + 1..];
+        }
+        self.rest = self.rest.strip_prefix('{')?;
+
+        let mut elements = vec![];
+        if let Some(rest) = self.rest.strip_prefix('}') {
+            self.rest = rest;
+            return Some(Value::Array(elements));
+        }
+        loop {
+            let value = if self.rest.starts_with('{') {
+                self.array(element)?
+            } else if let Some(rest) = self.rest.strip_prefix('"') {
+                self.rest = rest;
+                let mut text = String::new();
+                let mut chars = self.rest.char_indices();
+                loop {
+                    match chars.next()? {
+                        (_, '\\') => text.push(chars.next()?.1),
+                        (index, '"') => {
+                            self.rest = &self.rest[index + 1..];
+                            break;
+                        }
+                        (_, char) => text.push(char),
+                    }
+                }
+                Value::from_text(Some(element), &text).ok()?
+            } else {
+                let end = self.rest.find([',', '}'])?;
+                let text = &self.rest[..end];
+                self.rest = &self.rest[end..];
+                match text {
+                    "NULL" => Value::Null,
+                    text => Value::from_text(Some(element), text).ok()?,
+                }
+            };
+            elements.push(value);
+
+            if let Some(rest) = self.rest.strip_prefix(',') {
+                self.rest = rest;
+            } else {
+                self.rest = self.rest.strip_prefix('}')?;
+                return Some(Value::Array(elements));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::decoderbufs::Point;
+
+    #[test]
+    fn test_from_text() {
+        let id = "c497c1be-cf70-41aa-8665-971e2ffaefcd";
+        let cases = [
+            (oid::BOOL, "t", Value::Bool(true)),
+            (oid::BOOL, "false", Value::Bool(false)),
+            (oid::INT2, "-7", Value::Int2(-7)),
+            (oid::INT4, "3", Value::Int4(3)),
+            (
+                oid::INT8,
+                "9007199254740993",
+                Value::Int8(9_007_199_254_740_993),
+            ),
+            (oid::FLOAT8, "1.25", Value::Float(1.25)),
+            (oid::NUMERIC, "1.50", Value::Numeric("1.50".to_string())),
+            (oid::VARCHAR, "name", Value::Text("name".to_string())),
+            (oid::UUID, id, Value::Uuid(Uuid::parse_str(id).unwrap())),
+            (
+                oid::BYTEA,
+                "\\x0aff",
+                Value::Bytea(Bytes::from_static(&[0x0a, 0xff])),
+            ),
+            (
+                oid::TIMESTAMP,
+                "2022-11-10 12:00:00.5",
+                Value::Timestamp(
+                    NaiveDate::from_ymd_opt(2022, 11, 10)
+                        .unwrap()
+                        .and_hms_milli_opt(12, 0, 0, 500)
+                        .unwrap(),
+                ),
+            ),
+            (
+                oid::TIMESTAMPTZ,
+                "2022-11-10 14:00:00+02",
+                Value::Timestamptz(DateTime::from_timestamp(1_668_081_600, 0).unwrap()),
+            ),
+            (
+                oid::DATE,
+                "2022-11-10",
+                Value::Date(NaiveDate::from_ymd_opt(2022, 11, 10).unwrap()),
+            ),
+            (oid::DATE, "infinity", Value::Date(NaiveDate::MAX)),
+            (
+                oid::JSONB,
+                r#"{"a": [1, null]}"#,
+                Value::Json(serde_json::json!({"a": [1, null]})),
+            ),
+            (oid::POINT, "(1.5,-2)", Value::Point { x: 1.5, y: -2.0 }),
+            (
+                oid::INT4_ARRAY,
+                "{{1,2},{NULL,4}}",
+                Value::Array(vec![
+                    Value::Array(vec![Value::Int4(1), Value::Int4(2)]),
+                    Value::Array(vec![Value::Null, Value::Int4(4)]),
+                ]),
+            ),
+            (
+                oid::TEXT_ARRAY,
+                r#"{plain,"with \"quotes\", commas",""}"#,
+                Value::Array(vec![
+                    Value::Text("plain".to_string()),
+                    Value::Text("with \"quotes\", commas".to_string()),
+                    Value::Text("".to_string()),
+                ]),
+            ),
+            (oid::TEXT_ARRAY, "{}", Value::Array(vec![])),
+            (
+                oid::INT2_ARRAY,
+                "[0:1]={1,2}",
+                Value::Array(vec![Value::Int2(1), Value::Int2(2)]),
+            ),
+            // types without a dedicated variant keep their textual form
+            (790, "$1.00", Value::Text("$1.00".to_string())),
+        ];
+
+        for (type_oid, text, expected) in cases {
+            assert_eq!(
+                Value::from_text(Some(type_oid), text).unwrap(),
+                expected,
+                "{}",
+                text
+            );
+        }
+        assert_eq!(
+            Value::from_text(None, "anything").unwrap(),
+            Value::Text("anything".to_string())
+        );
+
+        for (type_oid, text) in [
+            (oid::BOOL, "yes"),
+            (oid::INT2, "40000"),
+            (oid::UUID, "not-a-uuid"),
+            (oid::BYTEA, "\\x0"),
+            (oid::TIMESTAMPTZ, "yesterday"),
+            (oid::INT4_ARRAY, "{1,2"),
+            (oid::INT4_ARRAY, "{1,a}"),
+        ] {
+            assert!(Value::from_text(Some(type_oid), text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_from_datum() {
+        let datum = |type_oid: u32, datum: Option<Datum>| DatumMessage {
+            column_name: Some("column".to_string()),
+            column_type: Some(type_oid as i64),
+            datum,
+        };
+        let cases = [
+            (datum(oid::TEXT, None), Value::Null),
+            (
+                datum(oid::TEXT, Some(Datum::DatumMissing(true))),
+                Value::UnchangedToast,
+            ),
+            (datum(oid::INT2, Some(Datum::DatumInt32(7))), Value::Int2(7)),
+            (datum(oid::INT4, Some(Datum::DatumInt32(7))), Value::Int4(7)),
+            (datum(oid::INT8, Some(Datum::DatumInt64(7))), Value::Int8(7)),
+            (
+                datum(oid::NUMERIC, Some(Datum::DatumDouble(1.5))),
+                Value::Numeric("1.5".to_string()),
+            ),
+            (
+                datum(oid::DATE, Some(Datum::DatumInt32(1))),
+                Value::Date(NaiveDate::from_ymd_opt(1970, 1, 2).unwrap()),
+            ),
+            (
+                datum(oid::TIMESTAMPTZ, Some(Datum::DatumInt64(1_500_000))),
+                Value::Timestamptz(DateTime::from_timestamp(1, 500_000_000).unwrap()),
+            ),
+            (
+                datum(
+                    oid::UUID,
+                    Some(Datum::DatumString(
+                        "c497c1be-cf70-41aa-8665-971e2ffaefcd".to_string(),
+                    )),
+                ),
+                Value::Uuid(Uuid::parse_str("c497c1be-cf70-41aa-8665-971e2ffaefcd").unwrap()),
+            ),
+            (
+                datum(
+                    oid::POINT,
+                    Some(Datum::DatumPoint(Point { x: 1.0, y: 2.0 })),
+                ),
+                Value::Point { x: 1.0, y: 2.0 },
+            ),
+        ];
+
+        for (datum, expected) in cases {
+            assert_eq!(Value::try_from(datum).unwrap(), expected);
+        }
+        assert!(Value::try_from(datum(oid::INT2, Some(Datum::DatumInt32(40_000)))).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 mod tenant;
-use crate::replication::decoderbufs::RowMessage;
+use crate::replication::Change;
 pub use tenant::Tenant;
 
 #[derive(Debug)]
@@ -7,20 +7,21 @@ pub use tenant::Tenant;
 struct Transaction {
     xid: u32,
     commit_time: u64,
-    changes: Vec<RowMessage>,
+    changes: Vec<Change>,
 }
 
 #[cfg(test)]
 mod test {
-    use crate::replication::Transaction;
+    use crate::replication::{
+        Acker, Acknowledgements, FromChange, Op, ReplicationConfig, Transaction,
+    };
 
     use super::Tenant;
-    use crate::replication::decoderbufs::{datum_message::Datum, Op};
     use anyhow::Result;
     use rand::{rngs::StdRng, Rng, SeedableRng};
     use sqlx::PgPool;
-    use std::{collections::HashMap, time::Duration};
-    use tokio::task;
+    use std::{collections::HashMap, env, sync::Arc, time::Duration};
+    use tokio::{sync::broadcast::error::RecvError, task};
     use tracing::trace;
     use uuid::Uuid;
 
@@ -31,7 +32,8 @@ mod test {
     }
 
     async fn subscriber(
-        tx: tokio::sync::broadcast::Sender<Transaction>,
+        tx: tokio::sync::broadcast::Sender<Arc<Transaction>>,
+        acker: Acker,
         mut done: tokio::sync::mpsc::Receiver<()>,
     ) -> (usize, HashMap<Uuid, Tenant>) {
         let mut rx = tx.subscribe();
@@ -43,129 +45,42 @@ mod test {
                 _ = done.recv() => {
                     break
                 }
-                Ok(transaction) = rx.recv() => {
+                transaction = rx.recv() => {
+                    let transaction = match transaction {
+                        Ok(transaction) => transaction,
+                        // a lagging subscriber has missed transactions so its tenants would drift
+                        Err(RecvError::Lagged(skipped)) => {
+                            panic!("subscriber lagged behind by {} transactions", skipped)
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
                     transactions += 1;
                                 println!("SUBSCRIBER {:?}", transaction.xid );
 
             transaction.events.iter().for_each(|event| {
-                match Op::from_i32(event.op.unwrap()).unwrap() {
+                let event = &*event.unwrap();
+                match event.op {
                     Op::Insert => {
-                        let inserts = event
-                            .new_tuple
-                            .iter()
-                            .map(|datum| (datum.column_name(), datum))
-                            .collect::<HashMap<_, _>>();
-
-                        let id = match &inserts.get("id").unwrap().datum.as_ref().unwrap() {
-                            Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                            _ => unimplemented!(),
-                        };
-
-                        tenants.insert(
-                            id,
-                            Tenant {
-                                xmin: Some(event.transaction_id() as i64),
-                                tenant_id: match &inserts
-                                    .get("tenant_id")
-                                    .unwrap()
-                                    .datum
-                                    .as_ref()
-                                    .unwrap()
-                                {
-                                    Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                                    _ => unimplemented!(),
-                                },
-                                id: match &inserts.get("id").unwrap().datum.as_ref().unwrap() {
-                                    Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                                    _ => unimplemented!(),
-                                },
-                                name: match &inserts.get("name").unwrap().datum.as_ref().unwrap() {
-                                    Datum::DatumString(value) => value.to_string(),
-                                    _ => unimplemented!(),
-                                },
-                                short_description: match &inserts
-                                    .get("short_description")
-                                    .unwrap()
-                                    .datum
-                                    .as_ref()
-                                {
-                                    Some(Datum::DatumString(value)) => Some(value.to_string()),
-                                    _ => None,
-                                },
-                                long_description: match &inserts
-                                    .get("long_description")
-                                    .unwrap()
-                                    .datum
-                                    .as_ref()
-                                {
-                                    Some(Datum::DatumString(value)) => Some(value.to_string()),
-                                    _ => None,
-                                },
-                            },
-                        );
+                        let mut tenant = Tenant::from_insert(event).unwrap();
+                        tenant.xmin = Some(transaction.xid as i64);
+                        tenants.insert(tenant.key(), tenant);
                     }
                     Op::Update => {
-                        let mut updates = event
-                            .new_tuple
-                            .iter()
-                            .map(|datum| (datum.column_name(), datum))
-                            .collect::<HashMap<_, _>>();
-
-                        let id = match &updates.remove("id").unwrap().datum.as_ref().unwrap() {
-                            Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                            _ => unimplemented!(),
-                        };
-
-                        let mut tenant = tenants.get_mut(&id).unwrap();
-                        tenant.xmin = Some(event.transaction_id() as i64);
-
-                        updates.into_iter().for_each(|(k, v)| match k {
-                            "tenant_id" => {
-                                tenant.tenant_id = match v.datum.as_ref().unwrap() {
-                                    Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                                    _ => unimplemented!(),
-                                }
-                            }
-                            "name" => {
-                                tenant.name = match v.datum.as_ref().unwrap() {
-                                    Datum::DatumString(value) => value.to_string(),
-                                    _ => unimplemented!(),
-                                }
-                            }
-                            "short_description" => {
-                                tenant.short_description = match v.datum.as_ref() {
-                                    Some(Datum::DatumString(value)) => Some(value.to_string()),
-                                    _ => None,
-                                }
-                            }
-                            "long_description" => {
-                                tenant.long_description = match v.datum.as_ref() {
-                                    Some(Datum::DatumString(value)) => Some(value.to_string()),
-                                    _ => None,
-                                }
-                            }
-                            k => panic!("unimplemented key: {:?}", k),
-                        })
+                        let id = Tenant::key_from_change(event).unwrap();
+                        let tenant = tenants.get_mut(&id).unwrap();
+                        tenant.apply_update(event).unwrap();
+                        tenant.xmin = Some(transaction.xid as i64);
                     }
                     Op::Delete => {
-                        let mut deletes = event
-                            .old_tuple
-                            .iter()
-                            .map(|datum| (datum.column_name(), datum))
-                            .collect::<HashMap<_, _>>();
-
-                        let id = match &deletes.remove("id").unwrap().datum.as_ref().unwrap() {
-                            Datum::DatumString(value) => Uuid::parse_str(&value).unwrap(),
-                            _ => unimplemented!(),
-                        };
-
-                        tenants.remove(&id);
+                        tenants.remove(&Tenant::key_from_delete(event).unwrap());
+                    }
+                    Op::Truncate => {
+                        tenants.clear();
                     }
-                    Op::Begin => unreachable!(),
-                    Op::Commit => unreachable!(),
-                    Op::Unknown => unreachable!(),
                 };
             });
+
+            acker.ack(&transaction);
                 }
             }
         }
@@ -188,13 +103,24 @@ mod test {
                 .current_database
                 .unwrap()
         };
+        let config =
+            ReplicationConfig::from_url(&env::var("DATABASE_URL")?)?.dbname(current_database);
 
         let (ready_tx, ready_rx) = tokio::sync::oneshot::channel::<()>();
-        let (tx, _) = tokio::sync::broadcast::channel::<Transaction>(100);
+        let (tx, _) = tokio::sync::broadcast::channel::<Arc<Transaction>>(100);
+
+        let acknowledgements = Acknowledgements::new();
+        let acker = acknowledgements.register();
 
         let tx_clone = tx.clone();
         let listener_handle = task::spawn(async move {
-            crate::replication::start_streaming_changes(current_database, ready_tx, tx_clone).await
+            crate::replication::start_streaming_changes(
+                config,
+                ready_tx,
+                tx_clone,
+                acknowledgements,
+            )
+            .await
         });
 
         // block waiting for replication
@@ -204,7 +130,8 @@ mod test {
 
         let (done_tx, done_rx) = tokio::sync::mpsc::channel::<()>(1);
 
-        let subscriber_handle = task::spawn(async move { subscriber(tx_clone, done_rx).await });
+        let subscriber_handle =
+            task::spawn(async move { subscriber(tx_clone, acker, done_rx).await });
 
         let db_clone = db.clone();
         let generator_handle = task::spawn(async move {
@@ -1,11 +1,14 @@
+use crate::replication::FromChange;
 use anyhow::Result;
 use sqlx::PgConnection;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, FromChange)]
 pub struct Tenant {
+    #[column(skip)]
     pub xmin: Option<i64>,
     pub tenant_id: Uuid,
+    #[key]
     pub id: Uuid,
     pub name: String,
     pub short_description: Option<String>,